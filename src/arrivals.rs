@@ -0,0 +1,134 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::models::Scenario;
+use crate::rebalance::DemandGrid;
+
+/// One row of an arrival list
+#[derive(Serialize, Deserialize)]
+struct Arrival {
+    customer_id: String,
+    /// Seconds of the runner's clock after launch
+    arrival: f64,
+}
+
+/// Returns where the arrival list of a scenario is stored
+pub fn arrival_list_path(inputs_dir: &Path, scenario_id: &str) -> anyhow::Result<PathBuf> {
+    crate::scenario_io::input_path(inputs_dir, scenario_id, "arrivals.csv")
+}
+
+/// When customers become known to the dispatcher. The runner has every customer from the
+/// start, so customers are hidden from the run until they arrive. Customers without an
+/// arrival time are there from the start.
+#[derive(Debug, Clone, Default)]
+pub struct ArrivalSchedule {
+    times: HashMap<String, f64>,
+}
+
+impl ArrivalSchedule {
+    /// Every zone of the scenario's area gets customers as a Poisson process with
+    /// `rate_per_minute`, in random order within the zone
+    pub fn poisson(scenario: &Scenario, rate_per_minute: f64, rng: &mut impl Rng) -> Self {
+        let grid = DemandGrid::covering(scenario);
+        let mut zones: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+        for customer in &scenario.customers {
+            zones
+                .entry(grid.cell_of(customer.coord_x, customer.coord_y))
+                .or_default()
+                .push(&customer.id);
+        }
+
+        let rate = rate_per_minute / 60.0;
+        let mut times = HashMap::new();
+        for customers in zones.values_mut() {
+            let mut at = 0.0;
+            while !customers.is_empty() {
+                // Exponentially distributed gaps between arrivals
+                at += -(1.0 - rng.gen::<f64>()).ln() / rate;
+                let customer = customers.swap_remove(rng.gen_range(0..customers.len()));
+                times.insert(customer.to_string(), at);
+            }
+        }
+        ArrivalSchedule { times }
+    }
+
+    /// Reads a CSV file with `customer_id` and `arrival` columns
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let times = csv::Reader::from_reader(content.as_bytes())
+            .deserialize()
+            .map(|row| row.map(|a: Arrival| (a.customer_id, a.arrival)))
+            .collect::<Result<_, _>>()?;
+        Ok(ArrivalSchedule { times })
+    }
+
+    /// Writes the schedule in the format [`ArrivalSchedule::load`] reads, earliest first
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut arrivals: Vec<_> = self.times.iter().collect();
+        arrivals.sort_by(|a, b| a.1.total_cmp(b.1));
+
+        let mut writer = csv::Writer::from_path(path)?;
+        for (customer_id, arrival) in arrivals {
+            writer.serialize(Arrival {
+                customer_id: customer_id.clone(),
+                arrival: *arrival,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The scenario as far as it is known `at` into the run
+    pub fn visible(&self, mut scenario: Scenario, at: Duration) -> Scenario {
+        let at = at.as_secs_f64();
+        scenario
+            .customers
+            .retain(|c| self.times.get(&c.id).is_none_or(|arrival| *arrival <= at));
+        scenario
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_customers_appear_over_time() {
+    use crate::models::Customer;
+
+    let customer = |id: usize| Customer {
+        id: format!("c{}", id),
+        coord_x: (id % 4) as f64,
+        coord_y: (id / 4) as f64,
+        awaiting_service: true,
+        ..Default::default()
+    };
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "CREATED".to_string(),
+        customers: (0..16).map(customer).collect(),
+        ..Default::default()
+    };
+
+    let schedule = ArrivalSchedule::poisson(&scenario, 6.0, &mut rand::thread_rng());
+    assert!(schedule
+        .visible(scenario.clone(), Duration::ZERO)
+        .customers
+        .is_empty());
+    let later = |secs| schedule.visible(scenario.clone(), Duration::from_secs(secs));
+    assert!(later(30).customers.len() <= later(3600).customers.len());
+    assert_eq!(later(u32::MAX as u64).customers.len(), 16);
+
+    let path = std::env::temp_dir().join(format!("arrivals-test-{}.csv", std::process::id()));
+    schedule.save(&path).unwrap();
+    let loaded = ArrivalSchedule::load(&path).unwrap();
+    assert_eq!(loaded.times, schedule.times);
+    fs::remove_file(path).unwrap();
+}
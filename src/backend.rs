@@ -1,44 +1,101 @@
-use std::error::Error;
+use std::time::Duration;
 
-use reqwest::Client;
+use reqwest::StatusCode;
 
+use crate::error::ClientError;
+use crate::http::{CallKind, ClientConfig, HttpClient};
 use crate::models::Scenario;
 
 #[derive(Debug, Clone)]
 pub struct BackendClient {
-    client: Client,
+    client: HttpClient,
     base_url: String,
 }
 
 impl BackendClient {
-    pub fn new(backend_server_base_url: &str) -> Self {
+    pub fn new(backend_server_base_url: &str, config: ClientConfig) -> Self {
         Self {
-            client: Client::new(),
+            client: HttpClient::new("backend", config),
             base_url: backend_server_base_url.trim_end_matches('/').to_string(),
         }
     }
 
+    /// Checks that the backend answers, see [`HttpClient::probe`]
+    pub async fn probe(&self, timeout: Duration) -> Result<Duration, ClientError> {
+        self.client.probe(&self.base_url, timeout).await
+    }
+
     pub async fn create_scenario(
         &self,
         num_vehicles: u64,
         num_customers: u64,
-    ) -> Result<Scenario, Box<dyn Error>> {
-        let response = self
+    ) -> Result<Scenario, ClientError> {
+        let url = format!(
+            "{}/scenario/create?numberOfVehicles={}&numberOfCustomers={}",
+            self.base_url, num_vehicles, num_customers
+        );
+        self.client
+            .call("create_scenario", CallKind::Write, |client| {
+                client.post(&url)
+            })
+            .await
+    }
+
+    /// Fetches a scenario as it is stored in the database. Backends without a route for
+    /// single scenarios answer 404, the scenario is then looked up in the full listing.
+    pub async fn get_scenario(&self, scenario_id: &str) -> Result<Scenario, ClientError> {
+        let url = format!("{}/scenarios/{}", self.base_url, scenario_id);
+        let result = self
             .client
-            .post(&format!(
-                "{}/scenario/create?numberOfVehicles={}&numberOfCustomers={}",
-                self.base_url, num_vehicles, num_customers
-            ))
-            .send()
-            .await?
-            .error_for_status()?;
-
-        // Print the response body
-        let response_body = response.text().await?;
-        println!("Response body: {}", response_body);
-
-        // Parse the response body into a Scenario
-        let scenario: Scenario = serde_json::from_str(&response_body)?;
-        Ok(scenario)
+            .call("get_scenario", CallKind::Read, |client| client.get(&url))
+            .await;
+
+        match result {
+            Err(ClientError::Status { status, .. }) if status == StatusCode::NOT_FOUND => {
+                let scenarios = self.get_scenarios().await?;
+                scenarios
+                    .into_iter()
+                    .find(|s| s.id == scenario_id)
+                    .ok_or_else(|| ClientError::Status {
+                        url,
+                        status,
+                        body: format!("No scenario {}", scenario_id),
+                    })
+            }
+            result => result,
+        }
     }
+
+    /// Fetches every scenario stored in the database
+    pub async fn get_scenarios(&self) -> Result<Vec<Scenario>, ClientError> {
+        let url = format!("{}/scenarios", self.base_url);
+        self.client
+            .call("get_scenarios", CallKind::Read, |client| client.get(&url))
+            .await
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[tokio::test]
+async fn test_scenarios_are_found_in_the_listing() {
+    use warp::Filter;
+
+    let scenario = |id: &str| Scenario {
+        id: id.to_string(),
+        status: "CREATED".to_string(),
+        ..Default::default()
+    };
+    let listing = vec![scenario("s1"), scenario("s2")];
+    // No route for single scenarios, like older backends
+    let routes = warp::path("scenarios")
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&listing));
+    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let client = BackendClient::new(&format!("http://{}", addr), ClientConfig::default());
+    assert_eq!(client.get_scenario("s2").await.unwrap().id, "s2");
+    let missing = client.get_scenario("s3").await.unwrap_err();
+    assert_eq!(missing.code(), "upstream_not_found");
 }
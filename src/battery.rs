@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::emissions::{EmissionFactors, Powertrain};
+use crate::matching::{COST_FUNCTION, TIME_FUNCTION};
+use crate::models::{Charger, Scenario};
+
+/// How electric vehicles use and recharge their batteries
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryModel {
+    /// For electric vehicles that don't state their battery capacity
+    pub capacity_kwh: f64,
+    pub consumption_kwh_per_km: f64,
+    /// Share of the capacity kept for reaching a charger
+    pub reserve: f64,
+}
+
+impl Default for BatteryModel {
+    fn default() -> Self {
+        BatteryModel {
+            capacity_kwh: 60.0,
+            consumption_kwh_per_km: 0.18,
+            reserve: 0.1,
+        }
+    }
+}
+
+impl BatteryModel {
+    /// The defaults, overridden by `BATTERY_CAPACITY_KWH`, `BATTERY_CONSUMPTION_KWH_PER_KM`
+    /// and `BATTERY_RESERVE`
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name).ok().map(|value| {
+                value
+                    .parse::<f64>()
+                    .unwrap_or_else(|_| panic!("{} env variable must be a number", name))
+            })
+        };
+        // Ranges and charging times are divided by these
+        let positive = |name: &str| {
+            var(name).inspect(|value| {
+                if !(value.is_finite() && *value > 0.0) {
+                    panic!("{} env variable must be a positive number", name);
+                }
+            })
+        };
+
+        let mut model = BatteryModel::default();
+        if let Some(capacity) = positive("BATTERY_CAPACITY_KWH") {
+            model.capacity_kwh = capacity;
+        }
+        if let Some(consumption) = positive("BATTERY_CONSUMPTION_KWH_PER_KM") {
+            model.consumption_kwh_per_km = consumption;
+        }
+        if let Some(reserve) = var("BATTERY_RESERVE") {
+            model.reserve = reserve.clamp(0.0, 1.0);
+        }
+        model
+    }
+}
+
+/// Returns where the charger list of a scenario is stored
+pub fn charger_list_path(inputs_dir: &Path, scenario_id: &str) -> anyhow::Result<PathBuf> {
+    crate::scenario_io::input_path(inputs_dir, scenario_id, crate::scenario_io::CHARGERS_CSV)
+}
+
+/// Reads a CSV file with `id`, `coordX`, `coordY` and `powerKw` columns
+pub fn load_chargers(path: &Path) -> anyhow::Result<Vec<Charger>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    crate::scenario_io::chargers_from_csv(&content)
+}
+
+/// An electric vehicle leaving service to charge
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChargingDetour {
+    pub vehicle_id: String,
+    pub charger_id: String,
+    /// Seconds of the runner's clock after launch when the vehicle is back with a full battery
+    pub until: f64,
+}
+
+#[derive(Debug)]
+struct Battery {
+    capacity: f64,
+    charge: f64,
+    distance: Option<f64>,
+    charging_until: Option<f64>,
+    /// The customer on board at the last call
+    customer_id: Option<String>,
+}
+
+/// Estimates the charge of every electric vehicle over a run. The runner has no batteries and
+/// cannot send vehicles to chargers, so a charging detour is emulated: the vehicle is out of
+/// service for as long as driving to the nearest charger and charging would take.
+#[derive(Debug)]
+pub struct Batteries {
+    model: BatteryModel,
+    batteries: HashMap<String, Battery>,
+    detours: Vec<ChargingDetour>,
+}
+
+impl Batteries {
+    /// Vehicles with a battery capacity and those electric by `factors` get a full battery
+    pub fn new(scenario: &Scenario, factors: &EmissionFactors, model: BatteryModel) -> Self {
+        let powertrains = factors.powertrains(scenario.vehicles.iter().map(|v| v.id.as_str()));
+        let batteries = scenario
+            .vehicles
+            .iter()
+            .zip(powertrains)
+            .filter_map(|(vehicle, powertrain)| {
+                let capacity = vehicle
+                    .battery_capacity
+                    .or((powertrain == Powertrain::Electric).then_some(model.capacity_kwh))?;
+                let battery = Battery {
+                    capacity,
+                    charge: vehicle.state_of_charge.unwrap_or(capacity).min(capacity),
+                    distance: None,
+                    charging_until: None,
+                    customer_id: None,
+                };
+                Some((vehicle.id.clone(), battery))
+            })
+            .collect();
+
+        Batteries {
+            model,
+            batteries,
+            detours: vec![],
+        }
+    }
+
+    /// Updates the charge from the distance driven since the last call and fills in the
+    /// battery of every electric vehicle. Vehicles low on charge go charging once they are
+    /// idle or have just dropped off a customer.
+    pub fn apply(&mut self, mut scenario: Scenario, at: Duration) -> Scenario {
+        let at = at.as_secs_f64();
+        let model = self.model;
+
+        for vehicle in &mut scenario.vehicles {
+            let Some(battery) = self.batteries.get_mut(&vehicle.id) else {
+                continue;
+            };
+
+            let distance = vehicle.distance_travelled.unwrap_or_default();
+            let driven = distance - battery.distance.unwrap_or(distance);
+            battery.distance = Some(distance);
+            battery.charge =
+                (battery.charge - driven / 1000.0 * model.consumption_kwh_per_km).max(0.0);
+
+            if battery.charging_until.is_some_and(|until| at >= until) {
+                battery.charging_until = None;
+                battery.charge = battery.capacity;
+            }
+
+            let reserve = battery.capacity * model.reserve;
+            let idle = vehicle.customer_id.is_none();
+            // The runner may have handed out the next customer in the same tick
+            let dropped_off =
+                battery.customer_id.is_some() && battery.customer_id != vehicle.customer_id;
+            battery.customer_id = vehicle.customer_id.clone();
+            if (idle || dropped_off)
+                && battery.charging_until.is_none()
+                && battery.charge <= reserve
+            {
+                let closest = scenario.chargers.iter().min_by(|a, b| {
+                    let distance = |c: &Charger| {
+                        COST_FUNCTION(vehicle.coord_x, vehicle.coord_y, c.coord_x, c.coord_y)
+                    };
+                    distance(a).total_cmp(&distance(b))
+                });
+                if let Some(charger) = closest {
+                    let drive = TIME_FUNCTION(
+                        vehicle.coord_x,
+                        vehicle.coord_y,
+                        charger.coord_x,
+                        charger.coord_y,
+                    );
+                    let charging = (battery.capacity - battery.charge) / charger.power_kw * 3600.0;
+                    let until = at + drive + charging;
+                    battery.charging_until = Some(until);
+                    self.detours.push(ChargingDetour {
+                        vehicle_id: vehicle.id.clone(),
+                        charger_id: charger.id.clone(),
+                        until,
+                    });
+                }
+            }
+
+            let usable = if battery.charging_until.is_some() {
+                0.0
+            } else {
+                (battery.charge - reserve).max(0.0)
+            };
+            vehicle.battery_capacity = Some(battery.capacity);
+            vehicle.state_of_charge = Some(battery.charge);
+            vehicle.range = Some(usable / model.consumption_kwh_per_km * 1000.0);
+            if battery.charging_until.is_some() && idle {
+                vehicle.is_available = false;
+            }
+        }
+        scenario
+    }
+
+    /// Detours started since the last call
+    pub fn take_detours(&mut self) -> Vec<ChargingDetour> {
+        std::mem::take(&mut self.detours)
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_empty_batteries_are_charged() {
+    use crate::models::Vehicle;
+
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 48.0,
+            coord_y: 11.5,
+            is_available: true,
+            distance_travelled: Some(0.0),
+            battery_capacity: Some(10.0),
+            ..Default::default()
+        }],
+        chargers: vec![Charger {
+            id: "ch1".to_string(),
+            coord_x: 48.0,
+            coord_y: 11.5,
+            power_kw: 36.0,
+        }],
+        ..Default::default()
+    };
+    let model = BatteryModel {
+        consumption_kwh_per_km: 0.2,
+        ..BatteryModel::default()
+    };
+    let mut batteries = Batteries::new(&scenario, &EmissionFactors::default(), model);
+
+    let start = batteries.apply(scenario.clone(), Duration::ZERO);
+    assert_eq!(start.vehicles[0].range, Some(45_000.0));
+    assert!(batteries.take_detours().is_empty());
+
+    // 46 km leave less than the reserve of 1 kWh
+    let mut drained = scenario.clone();
+    drained.vehicles[0].distance_travelled = Some(46_000.0);
+    let drained = batteries.apply(drained, Duration::from_secs(100));
+    assert!(!drained.vehicles[0].is_available);
+    assert_eq!(drained.vehicles[0].range, Some(0.0));
+    let detours = batteries.take_detours();
+    assert_eq!(detours.len(), 1);
+    // 9.2 kWh at 36 kW take 920 seconds
+    assert!((detours[0].until - 1020.0).abs() < 1e-6);
+
+    let mut charged = scenario;
+    charged.vehicles[0].distance_travelled = Some(46_000.0);
+    let charged = batteries.apply(charged, Duration::from_secs(1100));
+    assert!(charged.vehicles[0].is_available);
+    assert_eq!(charged.vehicles[0].state_of_charge, Some(10.0));
+}
+
+#[test]
+fn test_loaded_vehicles_charge_after_drop_off() {
+    use crate::models::Vehicle;
+
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 48.0,
+            coord_y: 11.5,
+            is_available: false,
+            customer_id: Some("c1".to_string()),
+            distance_travelled: Some(0.0),
+            battery_capacity: Some(10.0),
+            ..Default::default()
+        }],
+        chargers: vec![Charger {
+            id: "ch1".to_string(),
+            coord_x: 48.0,
+            coord_y: 11.5,
+            power_kw: 36.0,
+        }],
+        ..Default::default()
+    };
+    let model = BatteryModel {
+        consumption_kwh_per_km: 0.2,
+        ..BatteryModel::default()
+    };
+    let mut batteries = Batteries::new(&scenario, &EmissionFactors::default(), model);
+    batteries.apply(scenario.clone(), Duration::ZERO);
+
+    // Below the reserve with c1 still on board
+    let mut loaded = scenario.clone();
+    loaded.vehicles[0].distance_travelled = Some(46_000.0);
+    let loaded = batteries.apply(loaded, Duration::from_secs(100));
+    assert_eq!(loaded.vehicles[0].range, Some(0.0));
+    assert!(batteries.take_detours().is_empty());
+
+    // Dropping off c1 and picking up c2 within one tick still ends in a detour
+    let mut handed_over = loaded.clone();
+    handed_over.vehicles[0].distance_travelled = Some(47_000.0);
+    handed_over.vehicles[0].customer_id = Some("c2".to_string());
+    batteries.apply(handed_over, Duration::from_secs(200));
+    let detours = batteries.take_detours();
+    assert_eq!(detours.len(), 1);
+    // 9.4 kWh at 36 kW take 940 seconds
+    assert!((detours[0].until - 1140.0).abs() < 1e-6);
+
+    let mut idle = loaded;
+    idle.vehicles[0].distance_travelled = Some(48_000.0);
+    idle.vehicles[0].customer_id = None;
+    idle.vehicles[0].is_available = true;
+    let idle = batteries.apply(idle, Duration::from_secs(300));
+    assert!(!idle.vehicles[0].is_available);
+    assert!(batteries.take_detours().is_empty());
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use tracing::info;
+
+use crate::{
+    arrivals::ArrivalSchedule,
+    backend::BackendClient,
+    matching::pooling::{plan_pooled_routes, PoolingConfig},
+    scenario_io,
+};
+
+const USAGE: &str = "Usage:
+    t-systems-challenge                              start the web server
+    t-systems-challenge export <scenario_id> <path>  save a scenario from the backend
+    t-systems-challenge convert <input> <output>     convert between scenario formats
+    t-systems-challenge arrivals <scenario> <rate> <output>
+                                                     generate Poisson arrivals with <rate>
+                                                     customers per minute in every zone
+    t-systems-challenge pool <scenario> <capacity> <max_detour> <output.json>
+                                                     plan shared rides for the waiting customers
+
+Paths ending in .json are stored as JSON, .geojson as GeoJSON and
+anything else is a directory containing vehicles.csv and customers.csv";
+
+/// Runs the command given on the command line.
+/// Returns `None` if there was no command, in which case the web server should start.
+pub async fn run(args: &[String], backend_client: &BackendClient) -> Option<anyhow::Result<()>> {
+    let command = args.first()?;
+
+    Some(match (command.as_str(), &args[1..]) {
+        ("export", [scenario_id, output]) => export(backend_client, scenario_id, output).await,
+        ("convert", [input, output]) => convert(input, output),
+        ("arrivals", [input, rate, output]) => arrivals(input, rate, output),
+        ("pool", [input, capacity, max_detour, output]) => {
+            pool(input, capacity, max_detour, output)
+        }
+        _ => Err(anyhow!(USAGE)),
+    })
+}
+
+async fn export(
+    backend_client: &BackendClient,
+    scenario_id: &str,
+    output: &str,
+) -> anyhow::Result<()> {
+    let scenario = match backend_client.get_scenario(scenario_id).await {
+        Ok(s) => s,
+        Err(e) => bail!("Failed to fetch scenario {}: {}", scenario_id, e),
+    };
+
+    scenario_io::save(&scenario, Path::new(output))?;
+    info!("Saved scenario {} to {}", scenario_id, output);
+    Ok(())
+}
+
+fn convert(input: &str, output: &str) -> anyhow::Result<()> {
+    let scenario = scenario_io::load(Path::new(input))?;
+    scenario_io::save(&scenario, Path::new(output))?;
+    info!("Converted {} to {}", input, output);
+    Ok(())
+}
+
+fn arrivals(input: &str, rate: &str, output: &str) -> anyhow::Result<()> {
+    let rate: f64 = rate
+        .parse()
+        .ok()
+        .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+        .ok_or_else(|| anyhow!("The rate must be a positive number"))?;
+
+    let scenario = scenario_io::load(Path::new(input))?;
+    ArrivalSchedule::poisson(&scenario, rate, &mut rand::thread_rng()).save(Path::new(output))?;
+    info!(
+        "Saved arrivals of {} customers to {}",
+        scenario.customers.len(),
+        output
+    );
+    Ok(())
+}
+
+fn pool(input: &str, capacity: &str, max_detour: &str, output: &str) -> anyhow::Result<()> {
+    let default_capacity: u32 = capacity
+        .parse()
+        .ok()
+        .filter(|capacity| *capacity > 0)
+        .ok_or_else(|| anyhow!("The capacity must be a positive whole number"))?;
+    let max_detour: f64 = max_detour
+        .parse()
+        .ok()
+        .filter(|detour: &f64| detour.is_finite() && *detour >= 1.0)
+        .ok_or_else(|| anyhow!("The maximum detour must be a ratio of at least 1"))?;
+
+    let scenario = scenario_io::load(Path::new(input))?;
+    let plan = plan_pooled_routes(
+        &scenario,
+        PoolingConfig {
+            default_capacity,
+            max_detour,
+        },
+    );
+    std::fs::write(output, serde_json::to_string_pretty(&plan)?)?;
+    info!(
+        "Planned {} shared routes, {} customers left unassigned, saved to {}",
+        plan.routes.len(),
+        plan.unassigned.len(),
+        output
+    );
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::dispatch::Algorithm;
+use crate::models::Scenario;
+
+/// A command sent by a client over the websocket, e.g.
+/// `{"type": "assign", "id": "7", "vehicleId": "...", "customerId": "..."}`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CommandMessage {
+    /// Echoed in the reply so clients can match replies to commands
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+// Fields are renamed per variant, the OpenAPI schema doesn't pick up `rename_all_fields`
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Stop assigning customers, vehicles finish the trips they are on. A live run's clock
+    /// keeps going in the runner, only a replay stands still.
+    Pause,
+    Resume,
+    /// Playback speed multiplier of a replay. Live runs answer with an error: the runner
+    /// takes the speed when the scenario is launched and has no call to change it.
+    SetSpeed {
+        speed: f64,
+    },
+    /// Switch to another dispatcher, it takes over from the next tick
+    SetDispatcher {
+        algorithm: Algorithm,
+    },
+    /// Ask for the current scenario
+    Snapshot,
+    /// Make the next tick a full snapshot instead of a delta
+    Resync,
+    /// Send a free vehicle to a waiting customer, overriding the dispatcher
+    #[serde(rename_all = "camelCase")]
+    Assign {
+        vehicle_id: String,
+        customer_id: String,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::SetSpeed { .. } => "set_speed",
+            Command::SetDispatcher { .. } => "set_dispatcher",
+            Command::Snapshot => "snapshot",
+            Command::Resync => "resync",
+            Command::Assign { .. } => "assign",
+        }
+    }
+}
+
+/// Checks that a manual assignment can be sent to the runner
+pub fn validate_assignment(
+    scenario: &Scenario,
+    vehicle_id: &str,
+    customer_id: &str,
+) -> Result<(), String> {
+    let Some(vehicle) = scenario.vehicles.iter().find(|v| v.id == vehicle_id) else {
+        return Err(format!("Unknown vehicle {}", vehicle_id));
+    };
+    if vehicle.customer_id.is_some() {
+        return Err(format!(
+            "Vehicle {} is already serving a customer",
+            vehicle_id
+        ));
+    }
+
+    let Some(customer) = scenario.customers.iter().find(|c| c.id == customer_id) else {
+        return Err(format!("Unknown customer {}", customer_id));
+    };
+    if !customer.awaiting_service
+        || scenario
+            .vehicles
+            .iter()
+            .any(|v| v.customer_id.as_deref() == Some(customer_id))
+    {
+        return Err(format!("Customer {} is not waiting", customer_id));
+    }
+
+    Ok(())
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_parse_commands() {
+    let message: CommandMessage = serde_json::from_str(
+        r#"{"type": "assign", "id": "7", "vehicleId": "v1", "customerId": "c1"}"#,
+    )
+    .unwrap();
+    assert_eq!(message.id.as_deref(), Some("7"));
+    assert!(matches!(
+        message.command,
+        Command::Assign { ref vehicle_id, ref customer_id }
+            if vehicle_id == "v1" && customer_id == "c1"
+    ));
+
+    let message: CommandMessage =
+        serde_json::from_str(r#"{"type": "set_dispatcher", "algorithm": "ALSN"}"#).unwrap();
+    assert!(message.id.is_none());
+    assert!(matches!(
+        message.command,
+        Command::SetDispatcher {
+            algorithm: Algorithm::ALSN
+        }
+    ));
+
+    assert!(serde_json::from_str::<CommandMessage>(r#"{"type": "explode"}"#).is_err());
+}
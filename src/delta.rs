@@ -0,0 +1,308 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::events::Event;
+use crate::models::{Customer, Scenario, Vehicle};
+
+/// How many ticks may pass between two full snapshots if the client doesn't set it
+pub const DEFAULT_KEYFRAME_INTERVAL: u64 = 100;
+
+/// The fields of a vehicle that changed, all others are left out
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleDelta {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coord_x: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coord_y: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_available: Option<bool>,
+    /// `null` once the vehicle dropped off its customer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_id: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_travel_time: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_travelled: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_time: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number_of_trips: Option<Option<i64>>,
+    /// Seconds the driver may still drive, `null` for vehicles without a shift
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shift_remaining: Option<Option<f64>>,
+    /// `null` for vehicles without a battery model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_capacity: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_of_charge: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Option<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomerDelta {
+    pub id: String,
+    pub awaiting_service: bool,
+}
+
+/// Scenario-level fields that changed, plus the vehicles and customers that did
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScenarioDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+    pub vehicles: Vec<VehicleDelta>,
+    pub customers: Vec<CustomerDelta>,
+}
+
+/// Returns the new value if it differs from the old one
+fn changed<T: PartialEq + Clone>(old: &T, new: &T) -> Option<T> {
+    (old != new).then(|| new.clone())
+}
+
+fn vehicle_delta(old: &Vehicle, new: &Vehicle) -> Option<VehicleDelta> {
+    let delta = VehicleDelta {
+        id: new.id.clone(),
+        coord_x: changed(&old.coord_x, &new.coord_x),
+        coord_y: changed(&old.coord_y, &new.coord_y),
+        is_available: changed(&old.is_available, &new.is_available),
+        customer_id: changed(&old.customer_id, &new.customer_id),
+        remaining_travel_time: changed(&old.remaining_travel_time, &new.remaining_travel_time),
+        distance_travelled: changed(&old.distance_travelled, &new.distance_travelled),
+        active_time: changed(&old.active_time, &new.active_time),
+        number_of_trips: changed(&old.number_of_trips, &new.number_of_trips),
+        shift_remaining: changed(&old.shift_remaining, &new.shift_remaining),
+        battery_capacity: changed(&old.battery_capacity, &new.battery_capacity),
+        state_of_charge: changed(&old.state_of_charge, &new.state_of_charge),
+        range: changed(&old.range, &new.range),
+    };
+
+    let unchanged = delta.coord_x.is_none()
+        && delta.coord_y.is_none()
+        && delta.is_available.is_none()
+        && delta.customer_id.is_none()
+        && delta.remaining_travel_time.is_none()
+        && delta.distance_travelled.is_none()
+        && delta.active_time.is_none()
+        && delta.number_of_trips.is_none()
+        && delta.shift_remaining.is_none()
+        && delta.battery_capacity.is_none()
+        && delta.state_of_charge.is_none()
+        && delta.range.is_none();
+    (!unchanged).then_some(delta)
+}
+
+fn customer_delta(old: &Customer, new: &Customer) -> Option<CustomerDelta> {
+    (old.awaiting_service != new.awaiting_service).then(|| CustomerDelta {
+        id: new.id.clone(),
+        awaiting_service: new.awaiting_service,
+    })
+}
+
+/// Computes the changes between two states of the same scenario.
+/// Returns `None` if vehicles or customers were added or removed, which needs a full snapshot.
+pub fn diff(previous: &Scenario, current: &Scenario) -> Option<ScenarioDelta> {
+    if previous.vehicles.len() != current.vehicles.len()
+        || previous.customers.len() != current.customers.len()
+    {
+        return None;
+    }
+
+    let mut delta = ScenarioDelta {
+        status: changed(&previous.status, &current.status),
+        start_time: changed(&previous.start_time, &current.start_time).flatten(),
+        end_time: changed(&previous.end_time, &current.end_time).flatten(),
+        ..Default::default()
+    };
+
+    // The runner keeps the order of vehicles and customers stable
+    for (old, new) in previous.vehicles.iter().zip(current.vehicles.iter()) {
+        if old.id != new.id {
+            return None;
+        }
+        delta.vehicles.extend(vehicle_delta(old, new));
+    }
+    for (old, new) in previous.customers.iter().zip(current.customers.iter()) {
+        if old.id != new.id {
+            return None;
+        }
+        delta.customers.extend(customer_delta(old, new));
+    }
+
+    Some(delta)
+}
+
+/// Decides per tick whether a client gets a full snapshot or only the changes
+pub struct DeltaEncoder {
+    keyframe_interval: u64,
+    ticks_since_keyframe: u64,
+    resync: bool,
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_interval: u64) -> Self {
+        DeltaEncoder {
+            keyframe_interval: keyframe_interval.max(1),
+            ticks_since_keyframe: 0,
+            resync: false,
+        }
+    }
+
+    /// The next tick is sent as a full snapshot
+    pub fn request_resync(&mut self) {
+        self.resync = true;
+    }
+
+    pub fn encode(&mut self, previous: &Scenario, current: &Scenario) -> Event {
+        self.ticks_since_keyframe += 1;
+
+        if !self.resync && self.ticks_since_keyframe < self.keyframe_interval {
+            if let Some(delta) = diff(previous, current) {
+                return Event::Delta { delta };
+            }
+        }
+
+        self.resync = false;
+        self.ticks_since_keyframe = 0;
+        Event::Snapshot {
+            id: None,
+            scenario: current.clone(),
+        }
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[cfg(test)]
+fn two_vehicles() -> Scenario {
+    let vehicle = |id: &str| Vehicle {
+        id: id.to_string(),
+        coord_x: 48.1,
+        coord_y: 11.5,
+        is_available: true,
+        distance_travelled: Some(0.0),
+        ..Default::default()
+    };
+
+    Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![vehicle("v1"), vehicle("v2")],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_delta_only_contains_changes() {
+    let previous = two_vehicles();
+    let mut current = two_vehicles();
+    current.vehicles[1].coord_x = 48.2;
+    current.vehicles[1].customer_id = Some("c1".to_string());
+
+    let delta = diff(&previous, &current).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delta).unwrap(),
+        serde_json::json!({
+            "vehicles": [{"id": "v2", "coordX": 48.2, "customerId": "c1"}],
+            "customers": [],
+        })
+    );
+
+    let delivered = diff(&current, &previous).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delivered.vehicles[0]).unwrap()["customerId"],
+        serde_json::Value::Null
+    );
+}
+
+#[test]
+fn test_delta_tracks_shifts() {
+    let mut previous = two_vehicles();
+    previous.vehicles[0].shift_remaining = Some(600.0);
+    let mut current = previous.clone();
+    current.vehicles[0].shift_remaining = Some(540.0);
+
+    let delta = diff(&previous, &current).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delta.vehicles).unwrap(),
+        serde_json::json!([{"id": "v1", "shiftRemaining": 540.0}])
+    );
+
+    current.vehicles[0].shift_remaining = None;
+    let delta = diff(&previous, &current).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delta.vehicles).unwrap(),
+        serde_json::json!([{"id": "v1", "shiftRemaining": null}])
+    );
+}
+
+#[test]
+fn test_delta_tracks_batteries() {
+    let mut previous = two_vehicles();
+    previous.vehicles[1].battery_capacity = Some(60.0);
+    previous.vehicles[1].state_of_charge = Some(0.5);
+    previous.vehicles[1].range = Some(120_000.0);
+    let mut current = previous.clone();
+    current.vehicles[1].state_of_charge = Some(0.4);
+    current.vehicles[1].range = Some(90_000.0);
+
+    let delta = diff(&previous, &current).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delta.vehicles).unwrap(),
+        serde_json::json!([{"id": "v2", "stateOfCharge": 0.4, "range": 90_000.0}])
+    );
+
+    let delta = diff(&two_vehicles(), &current).unwrap();
+    assert_eq!(
+        serde_json::to_value(&delta.vehicles).unwrap(),
+        serde_json::json!([{
+            "id": "v2",
+            "batteryCapacity": 60.0,
+            "stateOfCharge": 0.4,
+            "range": 90_000.0,
+        }])
+    );
+}
+
+#[test]
+fn test_keyframes() {
+    let scenario = two_vehicles();
+    let mut encoder = DeltaEncoder::new(3);
+
+    assert!(matches!(
+        encoder.encode(&scenario, &scenario),
+        Event::Delta { .. }
+    ));
+    assert!(matches!(
+        encoder.encode(&scenario, &scenario),
+        Event::Delta { .. }
+    ));
+    assert!(matches!(
+        encoder.encode(&scenario, &scenario),
+        Event::Snapshot { .. }
+    ));
+    assert!(matches!(
+        encoder.encode(&scenario, &scenario),
+        Event::Delta { .. }
+    ));
+
+    encoder.request_resync();
+    assert!(matches!(
+        encoder.encode(&scenario, &scenario),
+        Event::Snapshot { .. }
+    ));
+
+    let mut grown = two_vehicles();
+    grown.vehicles.pop();
+    assert!(matches!(
+        encoder.encode(&scenario, &grown),
+        Event::Snapshot { .. }
+    ));
+}
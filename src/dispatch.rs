@@ -0,0 +1,470 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::matching::{
+    self,
+    pooling::{plan_pooled_routes, PooledRoute, PoolingConfig},
+};
+use crate::metrics::METRICS;
+use crate::models::{
+    Customer, Scenario, UpdateScenario, UpdateScenarioResponse, UpdateVehicle, Vehicle,
+};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum Algorithm {
+    Nearest,
+    ALSN,
+    /// Collects waiting customers and idle vehicles for a while, then matches them optimally
+    Batched,
+    /// Plans shared rides, the runner still carries one customer at a time
+    Pooled,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 4] = [
+        Algorithm::Nearest,
+        Algorithm::ALSN,
+        Algorithm::Batched,
+        Algorithm::Pooled,
+    ];
+}
+
+/// Decides which vehicles serve which customers, called once per simulation tick
+pub trait Dispatcher: Send {
+    /// Labels the dispatcher in metrics and logs
+    fn name(&self) -> &'static str;
+
+    fn dispatch(&mut self, scenario: &Scenario) -> UpdateScenario;
+
+    /// Called with the runner's answer to the update returned by the last `dispatch`
+    fn on_update(&mut self, _response: &UpdateScenarioResponse) {}
+
+    /// Drops any plan made earlier, the runner rejected some of its assignments
+    fn replan(&mut self) {}
+
+    /// Customers appeared since the last `dispatch`, plans made without them are outdated
+    fn on_arrivals(&mut self, _customers: &[Customer]) {
+        self.replan();
+    }
+
+    /// Shared rides planned by the last `dispatch`, for dispatchers that pool customers
+    fn take_routes(&mut self) -> Vec<PooledRoute> {
+        vec![]
+    }
+}
+
+/// `batch_window` is only used by [`Algorithm::Batched`]
+pub fn for_algorithm(algorithm: Algorithm, batch_window: BatchWindow) -> Box<dyn Dispatcher> {
+    match algorithm {
+        Algorithm::Nearest => Box::new(Nearest),
+        Algorithm::ALSN => Box::new(Alns::default()),
+        Algorithm::Batched => Box::new(Batched::new(batch_window)),
+        Algorithm::Pooled => Box::new(Pooled::default()),
+    }
+}
+
+/// Assigns every free vehicle the closest waiting customer
+pub struct Nearest;
+
+impl Dispatcher for Nearest {
+    fn name(&self) -> &'static str {
+        "nearest"
+    }
+
+    fn dispatch(&mut self, scenario: &Scenario) -> UpdateScenario {
+        update_scenario_first(scenario)
+    }
+}
+
+pub fn update_scenario_first(scenario: &Scenario) -> UpdateScenario {
+    // For each non-assigned vehicle, we assign the next available customer
+    let mut vehicle_assignments: Vec<UpdateVehicle> = Vec::new();
+
+    let (mut available_vehicles, unavailable_vehicles): (Vec<_>, Vec<_>) = scenario
+        .vehicles
+        .iter()
+        .partition(|vehicle| vehicle.customer_id.is_none());
+
+    let riding_customer_id_set = unavailable_vehicles
+        .iter()
+        .filter_map(|v| v.customer_id.clone())
+        .collect::<HashSet<_>>();
+
+    // Urgent classes pick their vehicles first, within a class the order stays as it was
+    let mut customers: Vec<&Customer> = scenario.customers.iter().collect();
+    customers.sort_by_key(|c| Reverse(c.priority));
+
+    for customer in customers {
+        if riding_customer_id_set.contains(&customer.id) || !customer.awaiting_service {
+            continue;
+        }
+
+        if available_vehicles.is_empty() {
+            break;
+        }
+        let vehicle = available_vehicles
+            .iter()
+            .filter(|v| matching::can_serve(v, customer))
+            .min_by_key(|v| {
+                let dx = v.coord_x - customer.coord_x;
+                let dy = v.coord_y - customer.coord_y;
+                (dx * dx + dy * dy).abs() as u64
+            });
+        // No vehicle can finish this trip within its shift and range
+        let Some(vehicle) = vehicle else {
+            continue;
+        };
+
+        vehicle_assignments.push(UpdateVehicle {
+            id: vehicle.id.clone(),
+            customer_id: vehicle.customer_id.clone().unwrap_or(customer.id.clone()),
+        });
+        available_vehicles.swap_remove(
+            available_vehicles
+                .iter()
+                .position(|v| v.id == vehicle.id)
+                .unwrap(),
+        );
+    }
+
+    UpdateScenario {
+        vehicles: vehicle_assignments,
+    }
+}
+
+/// Plans all routes with ALNS the first time it is asked, then hands out
+/// the next customer of a vehicle's route whenever that vehicle is free
+#[derive(Default)]
+pub struct Alns {
+    routes: Option<HashMap<String, VecDeque<String>>>,
+}
+
+impl Dispatcher for Alns {
+    fn name(&self) -> &'static str {
+        "alns"
+    }
+
+    fn dispatch(&mut self, scenario: &Scenario) -> UpdateScenario {
+        let routes = self.routes.get_or_insert_with(|| {
+            let _timer = METRICS.alns_solve_duration.start_timer();
+            matching::compute_assignment(scenario)
+        });
+
+        let waiting: HashSet<&str> = scenario
+            .customers
+            .iter()
+            .filter(|c| c.awaiting_service)
+            .map(|c| c.id.as_str())
+            .collect();
+        let mut taken: HashSet<String> = scenario
+            .vehicles
+            .iter()
+            .filter_map(|v| v.customer_id.clone())
+            .collect();
+
+        let mut vehicles = Vec::new();
+        let mut outdated = false;
+        for vehicle in scenario.vehicles.iter().filter(|v| v.customer_id.is_none()) {
+            let Some(route) = routes.get_mut(&vehicle.id) else {
+                continue;
+            };
+
+            // Customers may have been served by someone else in the meantime
+            while route
+                .front()
+                .is_some_and(|c| !waiting.contains(c.as_str()) || taken.contains(c))
+            {
+                route.pop_front();
+            }
+
+            if let Some(customer_id) = route.front() {
+                // The plan ignores shifts and batteries, the rest of the route goes to others
+                let customer = scenario.customers.iter().find(|c| &c.id == customer_id);
+                if customer.is_some_and(|c| !matching::can_serve(vehicle, c)) {
+                    route.clear();
+                    outdated = true;
+                    continue;
+                }
+
+                taken.insert(customer_id.clone());
+                vehicles.push(UpdateVehicle {
+                    id: vehicle.id.clone(),
+                    customer_id: customer_id.clone(),
+                });
+            }
+        }
+        if outdated {
+            self.replan();
+        }
+
+        UpdateScenario { vehicles }
+    }
+
+    fn on_update(&mut self, response: &UpdateScenarioResponse) {
+        let Some(routes) = self.routes.as_mut() else {
+            return;
+        };
+
+        for vehicle in &response.updated_vehicles {
+            let Some(route) = routes.get_mut(&vehicle.id) else {
+                continue;
+            };
+            if route.front() == vehicle.customer_id.as_ref() {
+                route.pop_front();
+            }
+        }
+    }
+
+    fn replan(&mut self) {
+        self.routes = None;
+    }
+}
+
+/// How long a batch collects customers and vehicles before they are matched
+#[derive(Debug, Clone, Copy)]
+pub struct BatchWindow {
+    /// Longest a customer waits for the batch to close
+    pub max_wait: Duration,
+    /// The batch closes early once this many vehicle and customer pairs are possible
+    pub max_pairs: usize,
+}
+
+impl Default for BatchWindow {
+    fn default() -> Self {
+        BatchWindow {
+            max_wait: Duration::from_secs(2),
+            max_pairs: 10,
+        }
+    }
+}
+
+impl BatchWindow {
+    /// The defaults, overridden by `BATCH_WINDOW_MS` and `BATCH_SIZE`
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{} env variable must be a number", name))
+            })
+        };
+
+        let mut window = BatchWindow::default();
+        if let Some(millis) = var("BATCH_WINDOW_MS") {
+            window.max_wait = Duration::from_millis(millis);
+        }
+        if let Some(pairs) = var("BATCH_SIZE") {
+            window.max_pairs = pairs.max(1) as usize;
+        }
+        window
+    }
+}
+
+/// Holds back assignments until the window closes, then assigns the whole batch at once
+/// with the smallest total distance to the pickups
+pub struct Batched {
+    window: BatchWindow,
+    /// When the first customer of the current batch could have been served
+    opened: Option<Instant>,
+}
+
+impl Batched {
+    pub fn new(window: BatchWindow) -> Self {
+        Batched {
+            window,
+            opened: None,
+        }
+    }
+}
+
+impl Dispatcher for Batched {
+    fn name(&self) -> &'static str {
+        "batched"
+    }
+
+    fn dispatch(&mut self, scenario: &Scenario) -> UpdateScenario {
+        let taken: HashSet<&str> = scenario
+            .vehicles
+            .iter()
+            .filter_map(|v| v.customer_id.as_deref())
+            .collect();
+        let idle: Vec<&Vehicle> = scenario
+            .vehicles
+            .iter()
+            .filter(|v| v.customer_id.is_none())
+            .collect();
+        let waiting: Vec<&Customer> = scenario
+            .customers
+            .iter()
+            .filter(|c| c.awaiting_service && !taken.contains(c.id.as_str()))
+            .collect();
+
+        let nothing = UpdateScenario { vehicles: vec![] };
+        if idle.is_empty() || waiting.is_empty() {
+            self.opened = None;
+            return nothing;
+        }
+
+        let opened = *self.opened.get_or_insert_with(Instant::now);
+        if opened.elapsed() < self.window.max_wait
+            && idle.len().min(waiting.len()) < self.window.max_pairs
+        {
+            return nothing;
+        }
+
+        self.opened = None;
+        UpdateScenario {
+            vehicles: matching::compute_batch_assignment(&idle, &waiting)
+                .into_iter()
+                .map(|(id, customer_id)| UpdateVehicle { id, customer_id })
+                .collect(),
+        }
+    }
+}
+
+/// Plans shared rides for all free vehicles on every tick and sends each vehicle to the first
+/// pickup of its route. The runner carries one customer at a time, so the rest of a route is
+/// only announced, and the next tick plans again from wherever the vehicles are.
+#[derive(Default)]
+pub struct Pooled {
+    config: PoolingConfig,
+    routes: Vec<PooledRoute>,
+}
+
+impl Dispatcher for Pooled {
+    fn name(&self) -> &'static str {
+        "pooled"
+    }
+
+    fn dispatch(&mut self, scenario: &Scenario) -> UpdateScenario {
+        let plan = plan_pooled_routes(scenario, self.config);
+        let vehicles = plan
+            .routes
+            .iter()
+            .filter_map(|route| {
+                // Routes always start with a pickup
+                route.stops.first().map(|stop| UpdateVehicle {
+                    id: route.vehicle_id.clone(),
+                    customer_id: stop.customer_id.clone(),
+                })
+            })
+            .collect();
+
+        self.routes = plan.routes;
+        UpdateScenario { vehicles }
+    }
+
+    fn take_routes(&mut self) -> Vec<PooledRoute> {
+        std::mem::take(&mut self.routes)
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[cfg(test)]
+fn scenario_with(vehicles: &[(f64, f64)], customers: &[(f64, f64)]) -> Scenario {
+    Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vehicles
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| Vehicle {
+                id: format!("v{}", i),
+                coord_x: *x,
+                coord_y: *y,
+                is_available: true,
+                ..Default::default()
+            })
+            .collect(),
+        customers: customers
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| Customer {
+                id: format!("c{}", i),
+                coord_x: *x,
+                coord_y: *y,
+                awaiting_service: true,
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_batches_wait_for_the_window() {
+    let scenario = scenario_with(
+        &[(48.00, 11.50), (48.10, 11.50)],
+        &[(48.04, 11.50), (47.99, 11.50)],
+    );
+
+    let mut waiting = Batched::new(BatchWindow {
+        max_wait: Duration::from_secs(3600),
+        max_pairs: 3,
+    });
+    assert!(waiting.dispatch(&scenario).vehicles.is_empty());
+
+    let mut full = Batched::new(BatchWindow {
+        max_wait: Duration::from_secs(3600),
+        max_pairs: 2,
+    });
+    let mut assignments: Vec<(String, String)> = full
+        .dispatch(&scenario)
+        .vehicles
+        .into_iter()
+        .map(|a| (a.id, a.customer_id))
+        .collect();
+    assignments.sort();
+    // Greedy would give c0 the closer v0 and send v1 the long way to c1
+    assert_eq!(
+        assignments,
+        [
+            ("v0".to_string(), "c1".to_string()),
+            ("v1".to_string(), "c0".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_urgent_customers_come_first() {
+    use crate::models::Priority;
+
+    let mut scenario = scenario_with(&[(48.00, 11.50)], &[(48.01, 11.50), (48.03, 11.50)]);
+    scenario.customers[1].priority = Priority::Medical;
+
+    let nearest = Nearest.dispatch(&scenario);
+    assert_eq!(nearest.vehicles[0].customer_id, "c1");
+
+    let mut batched = Batched::new(BatchWindow {
+        max_wait: Duration::ZERO,
+        max_pairs: 1,
+    });
+    assert_eq!(batched.dispatch(&scenario).vehicles[0].customer_id, "c1");
+}
+
+#[test]
+fn test_pooled_vehicles_start_with_the_first_pickup() {
+    // Both customers ride east, one vehicle takes them together
+    let mut scenario = scenario_with(&[(48.0, 11.5)], &[(48.01, 11.5), (48.02, 11.5)]);
+    scenario.customers[0].destination_x = Some(48.05);
+    scenario.customers[1].destination_x = Some(48.04);
+
+    let mut pooled = Pooled::default();
+    let update = pooled.dispatch(&scenario);
+    assert_eq!(update.vehicles.len(), 1);
+    assert_eq!(update.vehicles[0].id, "v0");
+    assert_eq!(update.vehicles[0].customer_id, "c0");
+
+    let routes = pooled.take_routes();
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].stops.len(), 4);
+    assert!(pooled.take_routes().is_empty());
+}
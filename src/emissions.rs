@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Powertrain {
+    Electric,
+    Combustion,
+}
+
+/// Grams of CO2 per kilometre for each powertrain and which vehicles are electric.
+/// The runner reports distances in metres.
+#[derive(Debug, Clone)]
+pub struct EmissionFactors {
+    pub electric_g_per_km: f64,
+    pub combustion_g_per_km: f64,
+    /// Share of the fleet that is electric, the vehicles with the lowest ids are picked
+    pub electric_share: f64,
+    /// Electric no matter the share
+    pub electric_vehicles: HashSet<String>,
+}
+
+impl Default for EmissionFactors {
+    fn default() -> Self {
+        EmissionFactors {
+            // Charged from an average European grid mix
+            electric_g_per_km: 60.0,
+            combustion_g_per_km: 160.0,
+            electric_share: 0.0,
+            electric_vehicles: HashSet::new(),
+        }
+    }
+}
+
+impl EmissionFactors {
+    /// The defaults, overridden by `EMISSIONS_ELECTRIC_G_PER_KM`, `EMISSIONS_COMBUSTION_G_PER_KM`,
+    /// `EMISSIONS_ELECTRIC_SHARE` and `EMISSIONS_ELECTRIC_VEHICLES` (comma separated ids)
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name).ok().map(|value| {
+                value
+                    .parse::<f64>()
+                    .unwrap_or_else(|_| panic!("{} env variable must be a number", name))
+            })
+        };
+
+        let mut factors = EmissionFactors::default();
+        if let Some(factor) = var("EMISSIONS_ELECTRIC_G_PER_KM") {
+            factors.electric_g_per_km = factor;
+        }
+        if let Some(factor) = var("EMISSIONS_COMBUSTION_G_PER_KM") {
+            factors.combustion_g_per_km = factor;
+        }
+        if let Some(share) = var("EMISSIONS_ELECTRIC_SHARE") {
+            factors.electric_share = share.clamp(0.0, 1.0);
+        }
+        if let Ok(ids) = std::env::var("EMISSIONS_ELECTRIC_VEHICLES") {
+            factors.electric_vehicles = ids
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        factors
+    }
+
+    /// The powertrain of every vehicle of a fleet, in the order of `vehicle_ids`
+    pub fn powertrains<'a>(
+        &self,
+        vehicle_ids: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<Powertrain> {
+        let ids: Vec<&str> = vehicle_ids.into_iter().collect();
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        let electric_count = (self.electric_share * ids.len() as f64).round() as usize;
+        let electric_by_share: HashSet<&str> = sorted.into_iter().take(electric_count).collect();
+
+        ids.iter()
+            .map(|id| {
+                if electric_by_share.contains(id) || self.electric_vehicles.contains(*id) {
+                    Powertrain::Electric
+                } else {
+                    Powertrain::Combustion
+                }
+            })
+            .collect()
+    }
+
+    /// Grams of CO2 for driving `distance` metres
+    pub fn co2_grams(&self, powertrain: Powertrain, distance: f64) -> f64 {
+        let factor = match powertrain {
+            Powertrain::Electric => self.electric_g_per_km,
+            Powertrain::Combustion => self.combustion_g_per_km,
+        };
+        distance / 1000.0 * factor
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_electric_share_and_overrides() {
+    let factors = EmissionFactors {
+        electric_share: 0.5,
+        electric_vehicles: HashSet::from(["d".to_string()]),
+        ..EmissionFactors::default()
+    };
+
+    let powertrains = factors.powertrains(["c", "a", "d", "b"]);
+    assert_eq!(
+        powertrains,
+        [
+            Powertrain::Combustion,
+            Powertrain::Electric,
+            Powertrain::Electric,
+            Powertrain::Electric
+        ]
+    );
+    assert_eq!(factors.co2_grams(Powertrain::Combustion, 2000.0), 320.0);
+}
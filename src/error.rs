@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+
+/// How much of a response body is kept in an error
+const EXCERPT_LENGTH: usize = 200;
+
+/// Why a call to the runner or the backend failed
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Request to {url} timed out")]
+    Timeout { url: String },
+    #[error("Request to {url} failed: {source}")]
+    Transport {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{url} answered with {status}: {body}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        /// The start of the response body
+        body: String,
+    },
+    #[error("Unexpected response from {url}: {source} in `{excerpt}`")]
+    Decode {
+        url: String,
+        #[source]
+        source: serde_json::Error,
+        /// The part of the payload around where decoding failed
+        excerpt: String,
+    },
+    #[error("The runner reported an error: {0}")]
+    Runner(String),
+    /// The circuit breaker is open after too many failed calls
+    #[error("The {service} is unavailable, trying again in {retry_in:?}")]
+    Unavailable {
+        service: &'static str,
+        retry_in: Duration,
+    },
+}
+
+impl ClientError {
+    pub(crate) fn from_reqwest(source: reqwest::Error) -> Self {
+        let url = source.url().map(|url| url.to_string()).unwrap_or_default();
+
+        if source.is_timeout() {
+            ClientError::Timeout { url }
+        } else {
+            ClientError::Transport { url, source }
+        }
+    }
+
+    /// The service might answer if asked again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Timeout { .. }
+            | ClientError::Transport { .. }
+            | ClientError::Unavailable { .. } => true,
+            ClientError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            ClientError::Decode { .. } | ClientError::Runner(_) => false,
+        }
+    }
+
+    /// The request certainly wasn't processed, so even a write can be sent again
+    pub fn was_not_processed(&self) -> bool {
+        match self {
+            ClientError::Transport { source, .. } => source.is_connect(),
+            ClientError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::SERVICE_UNAVAILABLE
+            }
+            ClientError::Unavailable { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Stable identifier for error responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::Timeout { .. } => "upstream_timeout",
+            ClientError::Transport { .. } => "upstream_unreachable",
+            ClientError::Status { status, .. } if *status == StatusCode::NOT_FOUND => {
+                "upstream_not_found"
+            }
+            ClientError::Status { .. } => "upstream_error",
+            ClientError::Decode { .. } => "upstream_invalid_response",
+            ClientError::Runner(_) => "runner_error",
+            ClientError::Unavailable { .. } => "upstream_unavailable",
+        }
+    }
+
+    /// The status to answer our own clients with when this error ends a request
+    pub fn http_status(&self) -> warp::http::StatusCode {
+        use warp::http::StatusCode as Http;
+
+        match self {
+            ClientError::Timeout { .. } => Http::GATEWAY_TIMEOUT,
+            ClientError::Unavailable { .. } => Http::SERVICE_UNAVAILABLE,
+            ClientError::Status { status, .. } if *status == StatusCode::NOT_FOUND => {
+                Http::NOT_FOUND
+            }
+            ClientError::Transport { .. }
+            | ClientError::Status { .. }
+            | ClientError::Decode { .. }
+            | ClientError::Runner(_) => Http::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Sends a request and decodes its JSON response
+pub async fn fetch_json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ClientError> {
+    let response = request.send().await.map_err(ClientError::from_reqwest)?;
+    let url = response.url().to_string();
+    let status = response.status();
+    let body = response.text().await.map_err(ClientError::from_reqwest)?;
+
+    if !status.is_success() {
+        return Err(ClientError::Status {
+            url,
+            status,
+            body: excerpt(&body, 0),
+        });
+    }
+
+    serde_json::from_str(&body).map_err(|source| ClientError::Decode {
+        url,
+        excerpt: excerpt(&body, error_offset(&body, &source)),
+        source,
+    })
+}
+
+/// The byte offset of a decoding error in `body`
+fn error_offset(body: &str, error: &serde_json::Error) -> usize {
+    let line_start: usize = body
+        .split_inclusive('\n')
+        .take(error.line().saturating_sub(1))
+        .map(str::len)
+        .sum();
+    (line_start + error.column().saturating_sub(1)).min(body.len())
+}
+
+/// Up to [`EXCERPT_LENGTH`] characters of `body`, starting a bit before `offset`
+fn excerpt(body: &str, offset: usize) -> String {
+    let mut start = offset.saturating_sub(EXCERPT_LENGTH / 2);
+    while !body.is_char_boundary(start) {
+        start -= 1;
+    }
+    body[start..].chars().take(EXCERPT_LENGTH).collect()
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_decode_errors_point_at_the_payload() {
+    let body = format!(
+        "{{\"padding\": \"{}\",\n \"id\": 42}}",
+        "x".repeat(EXCERPT_LENGTH * 2)
+    );
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct Payload {
+        padding: String,
+        id: String,
+    }
+    let error = serde_json::from_str::<Payload>(&body).unwrap_err();
+
+    let excerpt = excerpt(&body, error_offset(&body, &error));
+    assert!(excerpt.contains("\"id\": 42"));
+    assert!(excerpt.len() <= EXCERPT_LENGTH);
+}
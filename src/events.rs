@@ -0,0 +1,405 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use utoipa::ToSchema;
+use warp::filters::ws::Message;
+
+use crate::battery::ChargingDetour;
+use crate::control::CommandMessage;
+use crate::delta::{DeltaEncoder, ScenarioDelta};
+use crate::kpi::Kpis;
+use crate::matching::pooling::PooledRoute;
+use crate::models::{Scenario, Vehicle};
+use crate::rebalance::Reposition;
+
+/// Clients that don't ask for a protocol version get bare `Scenario` JSON on every tick
+pub const LEGACY_PROTOCOL: u32 = 1;
+/// Every message is an [`Envelope`], every tick is a full snapshot
+pub const ENVELOPE_PROTOCOL: u32 = 2;
+/// Ticks only contain what changed, with a full snapshot every few ticks
+pub const DELTA_PROTOCOL: u32 = 3;
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope<'a> {
+    pub version: u32,
+    /// Position in the session's stream of messages, clients pass the last one they got to
+    /// resume after reconnecting. Messages meant for a single client repeat the last position.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: &'a Event,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+// Fields are renamed per variant, the OpenAPI schema doesn't pick up `rename_all_fields`
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Sent first on every connection; reconnect with `session_id` and the last `seq` to resume.
+    /// `seq` is where the stream continues, `0` means it starts over with a snapshot.
+    #[serde(rename_all = "camelCase")]
+    Welcome { session_id: String, seq: u64 },
+    /// The full state, sent every tick (as a keyframe to delta clients) and in reply to `snapshot`
+    Snapshot {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        scenario: Scenario,
+    },
+    /// What changed since the last tick, only sent to clients using [`DELTA_PROTOCOL`]
+    Delta {
+        #[serde(flatten)]
+        delta: ScenarioDelta,
+    },
+    /// The runner accepted an assignment
+    #[serde(rename_all = "camelCase")]
+    Assignment {
+        vehicle_id: String,
+        customer_id: String,
+        /// Requested by a client instead of the dispatcher
+        manual: bool,
+    },
+    /// The vehicles whose state changed since the last tick, at most one per tick
+    VehicleUpdate { vehicles: Vec<Vehicle> },
+    #[serde(rename_all = "camelCase")]
+    CustomerPickedUp {
+        customer_id: String,
+        vehicle_id: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    CustomerDelivered {
+        customer_id: String,
+        vehicle_id: String,
+    },
+    /// A command was applied
+    Ack {
+        id: Option<String>,
+        command: &'static str,
+    },
+    /// A command was rejected or the run failed; `id` is set for command replies
+    Error { id: Option<String>, message: String },
+    /// Calls to a service keep failing, the run waits for it to come back
+    Degraded {
+        service: &'static str,
+        message: String,
+    },
+    /// The service answers again after being degraded
+    Recovered { service: &'static str },
+    /// Where idle vehicles should wait for expected demand, replacing earlier proposals.
+    /// The runner cannot move vehicles on its own, so these are advice for operators.
+    Reposition { moves: Vec<Reposition> },
+    /// Electric vehicles low on charge left service to charge, they come back with a full battery
+    Charging { detours: Vec<ChargingDetour> },
+    /// Shared rides planned by the pooling dispatcher, replacing earlier ones. The runner only
+    /// takes the first pickup of each route, the later stops are advice for operators.
+    SharedRides { routes: Vec<PooledRoute> },
+    /// The run is over, no more events follow
+    Finished { kpis: Kpis },
+}
+
+impl Event {
+    pub fn ack(message: &CommandMessage) -> Self {
+        Event::Ack {
+            id: message.id.clone(),
+            command: message.command.name(),
+        }
+    }
+
+    pub fn error(id: Option<String>, message: impl Into<String>) -> Self {
+        Event::Error {
+            id,
+            message: message.into(),
+        }
+    }
+}
+
+/// Derives what happened between two consecutive scenario states
+pub fn diff(previous: &Scenario, current: &Scenario) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut updated = Vec::new();
+
+    for vehicle in &current.vehicles {
+        let Some(old) = previous.vehicles.iter().find(|v| v.id == vehicle.id) else {
+            continue;
+        };
+
+        if let Some(customer_id) = &old.customer_id {
+            if vehicle.customer_id.as_ref() != Some(customer_id) {
+                events.push(Event::CustomerDelivered {
+                    customer_id: customer_id.clone(),
+                    vehicle_id: vehicle.id.clone(),
+                });
+            }
+        }
+
+        if old.coord_x != vehicle.coord_x
+            || old.coord_y != vehicle.coord_y
+            || old.is_available != vehicle.is_available
+            || old.customer_id != vehicle.customer_id
+            || old.remaining_travel_time != vehicle.remaining_travel_time
+        {
+            updated.push(vehicle.clone());
+        }
+    }
+    // A single event per tick, so large fleets don't flood the session's history
+    if !updated.is_empty() {
+        events.push(Event::VehicleUpdate { vehicles: updated });
+    }
+
+    for customer in &current.customers {
+        let was_waiting = previous
+            .customers
+            .iter()
+            .any(|c| c.id == customer.id && c.awaiting_service);
+        if was_waiting && !customer.awaiting_service {
+            events.push(Event::CustomerPickedUp {
+                customer_id: customer.id.clone(),
+                vehicle_id: current
+                    .vehicles
+                    .iter()
+                    .chain(previous.vehicles.iter())
+                    .find(|v| v.customer_id.as_ref() == Some(&customer.id))
+                    .map(|v| v.id.clone()),
+            });
+        }
+    }
+
+    events
+}
+
+/// Encodes events for one websocket client in the protocol version it asked for
+#[derive(Clone)]
+pub struct EventSender {
+    sender: Sender<Message>,
+    protocol: u32,
+    /// The position of the last stream message sent to this client
+    last_seq: Arc<AtomicU64>,
+    keyframes: Arc<Mutex<DeltaEncoder>>,
+}
+
+impl EventSender {
+    pub fn new(sender: Sender<Message>, protocol: u32, keyframe_interval: u64) -> Self {
+        EventSender {
+            sender,
+            protocol,
+            last_seq: Arc::new(AtomicU64::new(0)),
+            keyframes: Arc::new(Mutex::new(DeltaEncoder::new(keyframe_interval))),
+        }
+    }
+
+    /// Sends the state after a tick, either as a full snapshot or as what changed
+    /// since `previous`, the last state this client got
+    pub async fn send_tick(
+        &self,
+        seq: u64,
+        previous: &Scenario,
+        scenario: &Scenario,
+    ) -> Result<(), Box<dyn Error>> {
+        let state = if self.protocol >= DELTA_PROTOCOL {
+            self.keyframes.lock().unwrap().encode(previous, scenario)
+        } else {
+            Event::Snapshot {
+                id: None,
+                scenario: scenario.clone(),
+            }
+        };
+        self.send_at(seq, state).await
+    }
+
+    /// The position of the last stream message sent to this client
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Makes the next tick a full snapshot
+    pub fn request_resync(&self) {
+        self.keyframes.lock().unwrap().request_resync();
+    }
+
+    /// Sends a message of the session's stream
+    pub async fn send_at(&self, seq: u64, event: Event) -> Result<(), Box<dyn Error>> {
+        self.last_seq.store(seq, Ordering::SeqCst);
+        self.send(event).await
+    }
+
+    /// Sends a message to this client only, like a reply to one of its commands
+    pub async fn send(&self, event: Event) -> Result<(), Box<dyn Error>> {
+        let Some(message) = self.encode(&event)? else {
+            return Ok(());
+        };
+
+        let Ok(_) = self.sender.send(message).await else {
+            return Err("WebSocket disconnected".into());
+        };
+        Ok(())
+    }
+
+    fn encode(&self, event: &Event) -> anyhow::Result<Option<Message>> {
+        if self.protocol < ENVELOPE_PROTOCOL {
+            // Old clients only understand scenarios and the replies to their own commands
+            return Ok(match event {
+                Event::Snapshot { id: None, scenario } => Some(scenario.try_into()?),
+                Event::Snapshot { .. } | Event::Ack { .. } | Event::Error { .. } => {
+                    Some(Message::text(serde_json::to_string(event)?))
+                }
+                _ => None,
+            });
+        }
+
+        // Vehicle changes are already part of the delta
+        if self.protocol >= DELTA_PROTOCOL && matches!(event, Event::VehicleUpdate { .. }) {
+            return Ok(None);
+        }
+
+        let envelope = Envelope {
+            version: self.protocol.min(DELTA_PROTOCOL),
+            seq: self.last_seq.load(Ordering::SeqCst),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            event,
+        };
+        Ok(Some(Message::text(serde_json::to_string(&envelope)?)))
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[cfg(test)]
+fn scenario_with(vehicle_customer: Option<&str>, awaiting_service: bool) -> Scenario {
+    use crate::models::Customer;
+
+    Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 48.1,
+            coord_y: 11.5,
+            is_available: vehicle_customer.is_none(),
+            customer_id: vehicle_customer.map(str::to_string),
+            ..Default::default()
+        }],
+        customers: vec![Customer {
+            id: "c1".to_string(),
+            coord_x: 48.2,
+            coord_y: 11.6,
+            destination_x: Some(48.3),
+            destination_y: Some(11.7),
+            awaiting_service,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_diff_detects_pickup_and_delivery() {
+    let waiting = scenario_with(Some("c1"), true);
+    let riding = scenario_with(Some("c1"), false);
+    let delivered = scenario_with(None, false);
+
+    assert!(diff(&waiting, &waiting).is_empty());
+
+    let events = diff(&waiting, &riding);
+    assert!(matches!(
+        events.as_slice(),
+        [Event::CustomerPickedUp { customer_id, vehicle_id: Some(v) }]
+            if customer_id == "c1" && v == "v1"
+    ));
+
+    let events = diff(&riding, &delivered);
+    assert!(matches!(
+        events.as_slice(),
+        [Event::CustomerDelivered { .. }, Event::VehicleUpdate { .. }]
+    ));
+}
+
+#[test]
+fn test_vehicle_updates_are_batched_per_tick() {
+    let mut previous = scenario_with(None, true);
+    previous.vehicles = (0..500)
+        .map(|i| Vehicle {
+            id: format!("v{}", i),
+            coord_x: 48.1,
+            coord_y: 11.5,
+            is_available: true,
+            ..Default::default()
+        })
+        .collect();
+    let mut current = previous.clone();
+    for vehicle in current.vehicles.iter_mut().skip(100) {
+        vehicle.coord_x = 48.2;
+    }
+
+    let events = diff(&previous, &current);
+    assert_eq!(events.len(), 1);
+    let Event::VehicleUpdate { vehicles } = &events[0] else {
+        panic!("Expected a vehicle update, got {:?}", events[0]);
+    };
+    assert_eq!(vehicles.len(), 400);
+    assert_eq!(vehicles[0].id, "v100");
+}
+
+#[tokio::test]
+async fn test_envelope_encoding() {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+    let events = EventSender::new(sender, ENVELOPE_PROTOCOL, 1);
+    events
+        .send_at(
+            7,
+            Event::Snapshot {
+                id: None,
+                scenario: scenario_with(None, true),
+            },
+        )
+        .await
+        .unwrap();
+    events.send(Event::error(None, "boom")).await.unwrap();
+
+    let first: serde_json::Value =
+        serde_json::from_str(receiver.recv().await.unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(first["version"], 2);
+    assert_eq!(first["seq"], 7);
+    assert_eq!(first["scenario"]["id"], "s1");
+
+    let second: serde_json::Value =
+        serde_json::from_str(receiver.recv().await.unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(second["seq"], 7);
+    assert_eq!(second["type"], "error");
+    assert_eq!(second["message"], "boom");
+}
+
+#[tokio::test]
+async fn test_legacy_clients_get_bare_scenarios() {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+    let events = EventSender::new(sender, LEGACY_PROTOCOL, 1);
+    events
+        .send(Event::CustomerDelivered {
+            customer_id: "c1".to_string(),
+            vehicle_id: "v1".to_string(),
+        })
+        .await
+        .unwrap();
+    events
+        .send(Event::Snapshot {
+            id: None,
+            scenario: scenario_with(None, true),
+        })
+        .await
+        .unwrap();
+
+    let message: serde_json::Value =
+        serde_json::from_str(receiver.recv().await.unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(message["id"], "s1");
+    assert!(message.get("type").is_none());
+}
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::backend::BackendClient;
+use crate::dispatch::Algorithm;
+use crate::error::ClientError;
+use crate::runner::RunnerClient;
+
+/// How long a readiness probe waits for each service
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether one upstream service answered
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStatus {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<Duration, ClientError>> for ServiceStatus {
+    fn from(result: Result<Duration, ClientError>) -> Self {
+        match result {
+            Ok(latency) => ServiceStatus {
+                ok: true,
+                latency_ms: Some(latency.as_millis() as u64),
+                error: None,
+            },
+            Err(e) => ServiceStatus {
+                ok: false,
+                latency_ms: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+/// The server can only run scenarios if both the runner and the backend answer
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub runner: ServiceStatus,
+    pub backend: ServiceStatus,
+}
+
+impl Readiness {
+    /// Probes both services at the same time
+    pub async fn check(runner_client: &RunnerClient, backend_client: &BackendClient) -> Self {
+        let (runner, backend) = tokio::join!(
+            runner_client.probe(PROBE_TIMEOUT),
+            backend_client.probe(PROBE_TIMEOUT)
+        );
+        let (runner, backend) = (ServiceStatus::from(runner), ServiceStatus::from(backend));
+
+        Readiness {
+            ready: runner.ok && backend.ok,
+            runner,
+            backend,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// `unknown` if built outside a git checkout
+    pub git_hash: &'static str,
+    pub dispatchers: Vec<Algorithm>,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            dispatchers: Algorithm::ALL.to_vec(),
+        }
+    }
+}
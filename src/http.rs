@@ -0,0 +1,374 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use tokio::time::sleep;
+use tracing::{info_span, warn, Instrument};
+
+use crate::error::{fetch_json, ClientError};
+use crate::metrics::METRICS;
+
+/// Whether a call may be repeated without side effects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallKind {
+    /// GETs, retried on any transient failure
+    Read,
+    /// Calls that change state, only retried if the request certainly wasn't processed
+    Write,
+}
+
+/// Timeout and retries of one kind of call
+#[derive(Debug, Clone, Copy)]
+pub struct CallPolicy {
+    pub timeout: Duration,
+    /// Including the first try
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl CallPolicy {
+    /// Exponential backoff with jitter, so clients don't retry in lockstep
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    pub read: CallPolicy,
+    pub write: CallPolicy,
+    /// Failed calls in a row after which calls fail fast
+    pub breaker_threshold: u32,
+    /// How long calls fail fast before one is let through again
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(5),
+            read: CallPolicy {
+                timeout: Duration::from_secs(10),
+                max_attempts: 4,
+                base_delay: Duration::from_millis(200),
+                max_delay: Duration::from_secs(5),
+            },
+            write: CallPolicy {
+                timeout: Duration::from_secs(30),
+                max_attempts: 3,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(1),
+            },
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// The defaults, overridden by `<PREFIX>_CONNECT_TIMEOUT_MS`, `<PREFIX>_READ_TIMEOUT_MS`,
+    /// `<PREFIX>_WRITE_TIMEOUT_MS`, `<PREFIX>_READ_ATTEMPTS`, `<PREFIX>_WRITE_ATTEMPTS`,
+    /// `<PREFIX>_BREAKER_THRESHOLD` and `<PREFIX>_BREAKER_COOLDOWN_MS`
+    pub fn from_env(prefix: &str) -> Self {
+        let var = |name: &str| {
+            let name = format!("{}_{}", prefix, name);
+            std::env::var(&name).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{} env variable must be a number", name))
+            })
+        };
+        let millis = |name: &str| var(name).map(Duration::from_millis);
+
+        let mut config = ClientConfig::default();
+        if let Some(timeout) = millis("CONNECT_TIMEOUT_MS") {
+            config.connect_timeout = timeout;
+        }
+        if let Some(timeout) = millis("READ_TIMEOUT_MS") {
+            config.read.timeout = timeout;
+        }
+        if let Some(timeout) = millis("WRITE_TIMEOUT_MS") {
+            config.write.timeout = timeout;
+        }
+        if let Some(attempts) = var("READ_ATTEMPTS") {
+            config.read.max_attempts = attempts.max(1) as u32;
+        }
+        if let Some(attempts) = var("WRITE_ATTEMPTS") {
+            config.write.max_attempts = attempts.max(1) as u32;
+        }
+        if let Some(threshold) = var("BREAKER_THRESHOLD") {
+            config.breaker_threshold = threshold.max(1) as u32;
+        }
+        if let Some(cooldown) = millis("BREAKER_COOLDOWN_MS") {
+            config.breaker_cooldown = cooldown;
+        }
+        config
+    }
+
+    fn policy(&self, kind: CallKind) -> &CallPolicy {
+        match kind {
+            CallKind::Read => &self.read,
+            CallKind::Write => &self.write,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// Set while the one call let through after the cooldown is in flight. Expires after
+    /// another cooldown, in case the call never reports back.
+    probe_until: Option<Instant>,
+}
+
+/// Stops calling a service that keeps failing, letting a single call through every cooldown.
+/// Other calls keep failing fast until that call succeeded.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::default(),
+        }
+    }
+
+    /// How long to wait if calls should fail fast right now
+    fn check(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match (state.open_until, state.probe_until) {
+            (Some(until), _) if until > now => Err(until - now),
+            (_, Some(until)) if until > now => Err(until - now),
+            // Half open, this call finds out whether the service is back
+            (Some(_), _) => {
+                state.probe_until = Some(now + self.cooldown);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.probe_until = None;
+        if failed {
+            state.failures += 1;
+            if state.failures >= self.threshold {
+                state.open_until = Some(Instant::now() + self.cooldown);
+            }
+        } else {
+            *state = BreakerState::default();
+        }
+    }
+
+    /// Too many calls failed, until the next one succeeds
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().failures >= self.threshold
+    }
+}
+
+/// A `reqwest` client that applies timeouts, retries and a circuit breaker to every call
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client,
+    config: ClientConfig,
+    breaker: Arc<CircuitBreaker>,
+    /// Named in errors and degraded state
+    service: &'static str,
+}
+
+impl HttpClient {
+    pub fn new(service: &'static str, config: ClientConfig) -> Self {
+        HttpClient {
+            client: Client::builder()
+                .connect_timeout(config.connect_timeout)
+                .build()
+                .expect("Failed to create HTTP client"),
+            breaker: Arc::new(CircuitBreaker::new(
+                config.breaker_threshold,
+                config.breaker_cooldown,
+            )),
+            config,
+            service,
+        }
+    }
+
+    /// Sends the request built by `request`, again if it failed and `kind` allows it.
+    /// `endpoint` names the call in metrics.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        kind: CallKind,
+        request: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let started = Instant::now();
+        let span = info_span!("http", service = self.service, endpoint, ?kind);
+        let result = self
+            .call_with_retries(endpoint, kind, request)
+            .instrument(span)
+            .await;
+
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(e) => e.code(),
+        };
+        METRICS
+            .http_request_duration
+            .with_label_values(&[self.service, endpoint, outcome])
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+
+    async fn call_with_retries<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        kind: CallKind,
+        request: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let policy = self.config.policy(kind);
+        let mut attempt = 0;
+
+        loop {
+            if let Err(retry_in) = self.breaker.check() {
+                return Err(ClientError::Unavailable {
+                    service: self.service,
+                    retry_in,
+                });
+            }
+
+            let result = fetch_json(request(&self.client).timeout(policy.timeout)).await;
+            self.breaker
+                .record(matches!(&result, Err(e) if e.is_transient()));
+
+            let retry = match &result {
+                Err(e) if attempt + 1 < policy.max_attempts => match kind {
+                    CallKind::Read => e.is_transient(),
+                    CallKind::Write => e.was_not_processed(),
+                },
+                _ => false,
+            };
+            if !retry {
+                return result;
+            }
+
+            let delay = policy.backoff(attempt);
+            if let Err(e) = &result {
+                warn!(
+                    "Call to the {} failed (attempt {}), retrying in {:?}: {}",
+                    self.service,
+                    attempt + 1,
+                    delay,
+                    e
+                );
+            }
+            METRICS
+                .http_retries
+                .with_label_values(&[self.service, endpoint])
+                .inc();
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// The service failed too often lately
+    pub fn is_degraded(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    /// Checks that the service answers at all, without retries. Returns how long it took.
+    pub async fn probe(&self, url: &str, timeout: Duration) -> Result<Duration, ClientError> {
+        let started = Instant::now();
+        let response = self
+            .client
+            .get(url)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(ClientError::from_reqwest)?;
+
+        // Any answer short of a server error means it's up, even a 404 for the root path
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(ClientError::Status {
+                url: url.to_string(),
+                status,
+                body: String::new(),
+            });
+        }
+        Ok(started.elapsed())
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_circuit_breaker_opens_and_recovers() {
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+    breaker.record(true);
+    assert!(breaker.check().is_ok());
+    breaker.record(true);
+    assert!(breaker.is_open());
+    assert!(breaker.check().is_err());
+
+    breaker.record(false);
+    assert!(!breaker.is_open());
+    assert!(breaker.check().is_ok());
+}
+
+#[test]
+fn test_circuit_breaker_lets_one_call_through_after_the_cooldown() {
+    let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_millis(50)));
+    breaker.record(true);
+    std::thread::sleep(Duration::from_millis(60));
+
+    let barrier = Arc::new(std::sync::Barrier::new(2));
+    let callers: Vec<_> = (0..2)
+        .map(|_| {
+            let breaker = breaker.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                breaker.check().is_ok()
+            })
+        })
+        .collect();
+    let admitted: Vec<bool> = callers.into_iter().map(|c| c.join().unwrap()).collect();
+    assert_eq!(admitted.iter().filter(|&&ok| ok).count(), 1);
+
+    // A failed probe opens the breaker again, a successful one closes it
+    breaker.record(true);
+    assert!(breaker.check().is_err());
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.check().is_ok());
+    breaker.record(false);
+    assert!(breaker.check().is_ok());
+    assert!(breaker.check().is_ok());
+}
+
+#[test]
+fn test_backoff_grows_up_to_the_limit() {
+    let policy = ClientConfig::default().read;
+
+    assert!(policy.backoff(0) <= policy.base_delay);
+    assert!(policy.backoff(3) >= policy.base_delay * 4);
+    assert!(policy.backoff(30) <= policy.max_delay);
+}
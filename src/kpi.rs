@@ -0,0 +1,488 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::emissions::{EmissionFactors, Powertrain};
+use crate::models::{Priority, Scenario};
+
+/// Fleet-level outcome of a run. Times are seconds of the runner's clock since the run started.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Kpis {
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    pub vehicles: usize,
+    pub customers: usize,
+    /// Customers that were picked up
+    pub customers_served: usize,
+    pub customers_delivered: usize,
+    pub total_trips: i64,
+    pub total_distance: f64,
+    /// Driven without a customer on board, on the way to a pickup or idling
+    pub empty_distance: f64,
+    /// The part of the empty distance driven to pick up an assigned customer
+    pub approach_distance: f64,
+    pub loaded_distance: f64,
+    pub co2_grams: f64,
+    /// Emitted while driving empty
+    pub empty_co2_grams: f64,
+    pub total_active_time: f64,
+    pub run_time: f64,
+    /// Mean share of the run the vehicles were active
+    pub utilisation: Option<f64>,
+    /// When the last customer was delivered
+    pub completion_time: Option<f64>,
+    /// From the first snapshot a customer waited in until their pickup
+    pub wait_time: Option<Distribution>,
+    /// From pickup to delivery
+    pub ride_time: Option<Distribution>,
+    /// Distance and emissions while each dispatcher was in charge
+    pub per_dispatcher: Vec<DispatcherKpis>,
+    /// Wait and ride times of every priority class that had customers, to check SLAs against
+    pub per_priority: Vec<PriorityKpis>,
+    pub per_vehicle: Vec<VehicleKpis>,
+    pub per_customer: Vec<CustomerKpis>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DispatcherKpis {
+    pub dispatcher: String,
+    pub distance: f64,
+    pub empty_distance: f64,
+    pub loaded_distance: f64,
+    pub co2_grams: f64,
+    pub empty_co2_grams: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PriorityKpis {
+    pub priority: Priority,
+    pub customers: usize,
+    pub customers_served: usize,
+    pub wait_time: Option<Distribution>,
+    pub ride_time: Option<Distribution>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleKpis {
+    pub id: String,
+    pub trips: i64,
+    pub distance: f64,
+    pub empty_distance: f64,
+    pub approach_distance: f64,
+    pub loaded_distance: f64,
+    pub powertrain: Powertrain,
+    pub co2_grams: f64,
+    pub active_time: f64,
+    pub utilisation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomerKpis {
+    pub id: String,
+    pub priority: Priority,
+    pub vehicle_id: Option<String>,
+    pub wait_time: Option<f64>,
+    pub ride_time: Option<f64>,
+}
+
+/// Summary of a set of durations
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    pub fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+
+        // Nearest rank
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+            values[rank.clamp(1, values.len()) - 1]
+        };
+        Some(Distribution {
+            count: values.len(),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p95: percentile(95.0),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// Who gets the distance driven before any dispatcher was named, e.g. in old recordings
+const UNKNOWN_DISPATCHER: &str = "unknown";
+
+#[derive(Debug, Clone)]
+struct CustomerTimes {
+    waiting_since: f64,
+    picked_up: Option<f64>,
+    delivered: Option<f64>,
+    vehicle_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct VehicleLegs {
+    powertrain: Powertrain,
+    distance: f64,
+    empty: f64,
+    approach: f64,
+    loaded: f64,
+    /// The customer on board at the last snapshot
+    carrying: Option<String>,
+    /// Assigned at the last snapshot, but not picked up yet
+    approaching: bool,
+}
+
+/// Follows a run snapshot by snapshot. The runner only reports totals, so everything
+/// per customer and per leg is derived from how consecutive snapshots differ.
+#[derive(Debug, Clone, Default)]
+pub struct KpiTracker {
+    factors: Arc<EmissionFactors>,
+    customers: HashMap<String, CustomerTimes>,
+    vehicles: HashMap<String, VehicleLegs>,
+    dispatchers: BTreeMap<String, DispatcherKpis>,
+    /// In charge since the last snapshot
+    dispatcher: Option<String>,
+    run_time: f64,
+}
+
+impl KpiTracker {
+    pub fn new(factors: Arc<EmissionFactors>) -> Self {
+        KpiTracker {
+            factors,
+            ..KpiTracker::default()
+        }
+    }
+
+    /// Takes in the scenario as it was `at` into the run, `dispatcher` deciding the next moves
+    pub fn observe(&mut self, at: Duration, dispatcher: Option<&str>, scenario: &Scenario) {
+        let at = at.as_secs_f64();
+        self.run_time = self.run_time.max(at);
+
+        if scenario
+            .vehicles
+            .iter()
+            .any(|v| !self.vehicles.contains_key(&v.id))
+        {
+            let powertrains = self
+                .factors
+                .powertrains(scenario.vehicles.iter().map(|v| v.id.as_str()));
+            for (vehicle, powertrain) in scenario.vehicles.iter().zip(powertrains) {
+                self.vehicles
+                    .entry(vehicle.id.clone())
+                    .or_insert(VehicleLegs {
+                        powertrain,
+                        distance: vehicle.distance_travelled.unwrap_or_default(),
+                        empty: 0.0,
+                        approach: 0.0,
+                        loaded: 0.0,
+                        carrying: None,
+                        approaching: false,
+                    });
+            }
+        }
+        let name = self
+            .dispatcher
+            .as_deref()
+            .or(dispatcher)
+            .unwrap_or(UNKNOWN_DISPATCHER)
+            .to_string();
+        let mut by_dispatcher = self
+            .dispatchers
+            .remove(&name)
+            .unwrap_or_else(|| DispatcherKpis {
+                dispatcher: name.clone(),
+                ..DispatcherKpis::default()
+            });
+
+        for customer in &scenario.customers {
+            // Customers that were never seen waiting have no known wait time
+            if customer.awaiting_service {
+                self.customers
+                    .entry(customer.id.clone())
+                    .or_insert(CustomerTimes {
+                        waiting_since: at,
+                        picked_up: None,
+                        delivered: None,
+                        vehicle_id: None,
+                    });
+            } else if let Some(times) = self.customers.get_mut(&customer.id) {
+                if times.picked_up.is_none() {
+                    times.picked_up = Some(at);
+                    times.vehicle_id = scenario
+                        .vehicles
+                        .iter()
+                        .find(|v| v.customer_id.as_ref() == Some(&customer.id))
+                        .map(|v| v.id.clone());
+                }
+            }
+        }
+
+        for vehicle in &scenario.vehicles {
+            let Some(legs) = self.vehicles.get_mut(&vehicle.id) else {
+                continue;
+            };
+
+            // What was driven since the last snapshot counts for the leg the vehicle was on then,
+            // and for the dispatcher that sent it there
+            let distance = vehicle.distance_travelled.unwrap_or(legs.distance);
+            let driven = (distance - legs.distance).max(0.0);
+            let co2 = self.factors.co2_grams(legs.powertrain, driven);
+            by_dispatcher.distance += driven;
+            by_dispatcher.co2_grams += co2;
+            if legs.carrying.is_some() {
+                legs.loaded += driven;
+                by_dispatcher.loaded_distance += driven;
+            } else {
+                legs.empty += driven;
+                by_dispatcher.empty_distance += driven;
+                by_dispatcher.empty_co2_grams += co2;
+                if legs.approaching {
+                    legs.approach += driven;
+                }
+            }
+            legs.distance = distance;
+
+            if let Some(delivered) = legs.carrying.take() {
+                if vehicle.customer_id.as_ref() != Some(&delivered) {
+                    if let Some(times) = self.customers.get_mut(&delivered) {
+                        times.delivered = Some(at);
+                    }
+                } else {
+                    legs.carrying = Some(delivered);
+                }
+            }
+            if legs.carrying.is_none() {
+                legs.carrying = vehicle.customer_id.clone().filter(|id| {
+                    scenario
+                        .customers
+                        .iter()
+                        .any(|c| &c.id == id && !c.awaiting_service)
+                });
+            }
+            legs.approaching = legs.carrying.is_none() && vehicle.customer_id.is_some();
+        }
+
+        if name != UNKNOWN_DISPATCHER || by_dispatcher.distance > 0.0 {
+            self.dispatchers.insert(name, by_dispatcher);
+        }
+        self.dispatcher = dispatcher.map(str::to_string);
+    }
+
+    /// The KPIs so far, `scenario` being the latest snapshot
+    pub fn kpis(&self, scenario: &Scenario) -> Kpis {
+        let utilisation = |active_time: f64| {
+            (self.run_time > 0.0).then(|| (active_time / self.run_time).min(1.0))
+        };
+
+        let per_vehicle: Vec<VehicleKpis> = scenario
+            .vehicles
+            .iter()
+            .map(|vehicle| {
+                let legs = self.vehicles.get(&vehicle.id);
+                let powertrain = legs.map_or(Powertrain::Combustion, |l| l.powertrain);
+                let active_time = vehicle.active_time.unwrap_or_default();
+                let empty_distance = legs.map_or(0.0, |l| l.empty);
+                let loaded_distance = legs.map_or(0.0, |l| l.loaded);
+                VehicleKpis {
+                    id: vehicle.id.clone(),
+                    trips: vehicle.number_of_trips.unwrap_or_default(),
+                    distance: vehicle.distance_travelled.unwrap_or_default(),
+                    empty_distance,
+                    approach_distance: legs.map_or(0.0, |l| l.approach),
+                    loaded_distance,
+                    powertrain,
+                    co2_grams: self
+                        .factors
+                        .co2_grams(powertrain, empty_distance + loaded_distance),
+                    active_time,
+                    utilisation: utilisation(active_time),
+                }
+            })
+            .collect();
+
+        let per_customer: Vec<CustomerKpis> = scenario
+            .customers
+            .iter()
+            .map(|customer| {
+                let times = self.customers.get(&customer.id);
+                CustomerKpis {
+                    id: customer.id.clone(),
+                    priority: customer.priority,
+                    vehicle_id: times.and_then(|t| t.vehicle_id.clone()),
+                    wait_time: times.and_then(|t| Some(t.picked_up? - t.waiting_since)),
+                    ride_time: times.and_then(|t| Some(t.delivered? - t.picked_up?)),
+                }
+            })
+            .collect();
+
+        let mut classes: BTreeMap<Priority, Vec<&CustomerKpis>> = BTreeMap::new();
+        for customer in &per_customer {
+            classes.entry(customer.priority).or_default().push(customer);
+        }
+        let per_priority = classes
+            .into_iter()
+            .map(|(priority, customers)| PriorityKpis {
+                priority,
+                customers: customers.len(),
+                customers_served: customers.iter().filter(|c| c.wait_time.is_some()).count(),
+                wait_time: Distribution::of(customers.iter().filter_map(|c| c.wait_time).collect()),
+                ride_time: Distribution::of(customers.iter().filter_map(|c| c.ride_time).collect()),
+            })
+            .collect();
+
+        let fleet_utilisation: Vec<f64> =
+            per_vehicle.iter().filter_map(|v| v.utilisation).collect();
+
+        Kpis {
+            start_time: scenario.start_time.clone(),
+            end_time: scenario.end_time.clone(),
+            vehicles: scenario.vehicles.len(),
+            customers: scenario.customers.len(),
+            customers_served: scenario
+                .customers
+                .iter()
+                .filter(|c| !c.awaiting_service)
+                .count(),
+            customers_delivered: self
+                .customers
+                .values()
+                .filter(|t| t.delivered.is_some())
+                .count(),
+            total_trips: per_vehicle.iter().map(|v| v.trips).sum(),
+            total_distance: per_vehicle.iter().map(|v| v.distance).sum(),
+            empty_distance: per_vehicle.iter().map(|v| v.empty_distance).sum(),
+            approach_distance: per_vehicle.iter().map(|v| v.approach_distance).sum(),
+            loaded_distance: per_vehicle.iter().map(|v| v.loaded_distance).sum(),
+            co2_grams: per_vehicle.iter().map(|v| v.co2_grams).sum(),
+            empty_co2_grams: per_vehicle
+                .iter()
+                .map(|v| self.factors.co2_grams(v.powertrain, v.empty_distance))
+                .sum(),
+            total_active_time: per_vehicle.iter().map(|v| v.active_time).sum(),
+            run_time: self.run_time,
+            utilisation: (!fleet_utilisation.is_empty())
+                .then(|| fleet_utilisation.iter().sum::<f64>() / fleet_utilisation.len() as f64),
+            completion_time: self
+                .customers
+                .values()
+                .filter_map(|t| t.delivered)
+                .max_by(f64::total_cmp),
+            wait_time: Distribution::of(per_customer.iter().filter_map(|c| c.wait_time).collect()),
+            ride_time: Distribution::of(per_customer.iter().filter_map(|c| c.ride_time).collect()),
+            per_dispatcher: self.dispatchers.values().cloned().collect(),
+            per_priority,
+            per_vehicle,
+            per_customer,
+        }
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[cfg(test)]
+fn snapshot(
+    customer_waiting: bool,
+    vehicle_customer: Option<&str>,
+    distance: f64,
+    active_time: f64,
+) -> Scenario {
+    use crate::models::{Customer, Vehicle};
+
+    Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 0.0,
+            coord_y: 0.0,
+            is_available: vehicle_customer.is_none(),
+            customer_id: vehicle_customer.map(str::to_string),
+            distance_travelled: Some(distance),
+            active_time: Some(active_time),
+            number_of_trips: Some(0),
+            ..Default::default()
+        }],
+        customers: vec![Customer {
+            id: "c1".to_string(),
+            coord_x: 0.0,
+            coord_y: 0.0,
+            awaiting_service: customer_waiting,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_tracker_follows_a_trip() {
+    let mut tracker = KpiTracker::default();
+    let secs = Duration::from_secs;
+
+    tracker.observe(secs(0), Some("nearest"), &snapshot(true, None, 0.0, 0.0));
+    // Assigned, on the way to the pickup
+    tracker.observe(
+        secs(10),
+        Some("nearest"),
+        &snapshot(true, Some("c1"), 0.0, 0.0),
+    );
+    tracker.observe(
+        secs(30),
+        Some("alns"),
+        &snapshot(false, Some("c1"), 200.0, 20.0),
+    );
+    tracker.observe(secs(70), Some("alns"), &snapshot(false, None, 600.0, 60.0));
+    let kpis = tracker.kpis(&snapshot(false, None, 600.0, 60.0));
+
+    assert_eq!(kpis.customers_delivered, 1);
+    assert_eq!(kpis.per_customer[0].vehicle_id.as_deref(), Some("v1"));
+    assert_eq!(kpis.per_customer[0].wait_time, Some(30.0));
+    assert_eq!(kpis.per_customer[0].ride_time, Some(40.0));
+    assert_eq!(kpis.empty_distance, 200.0);
+    assert_eq!(kpis.approach_distance, 200.0);
+    assert_eq!(kpis.loaded_distance, 400.0);
+    // 600m with a combustion engine
+    assert_eq!(kpis.co2_grams, 96.0);
+    let dispatchers: Vec<(&str, f64)> = kpis
+        .per_dispatcher
+        .iter()
+        .map(|d| (d.dispatcher.as_str(), d.empty_distance))
+        .collect();
+    assert_eq!(dispatchers, [("alns", 0.0), ("nearest", 200.0)]);
+    assert_eq!(kpis.per_priority.len(), 1);
+    assert_eq!(kpis.per_priority[0].priority, Priority::Standard);
+    assert_eq!(kpis.per_priority[0].wait_time.as_ref().unwrap().max, 30.0);
+    assert_eq!(kpis.completion_time, Some(70.0));
+    assert_eq!(kpis.utilisation, Some(60.0 / 70.0));
+}
+
+#[test]
+fn test_distribution_percentiles() {
+    let distribution = Distribution::of((1..=100).map(f64::from).collect()).unwrap();
+
+    assert_eq!(distribution.p50, 50.0);
+    assert_eq!(distribution.p95, 95.0);
+    assert_eq!(distribution.max, 100.0);
+    assert_eq!(distribution.mean, 50.5);
+    assert!(Distribution::of(vec![]).is_none());
+}
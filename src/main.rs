@@ -1,197 +1,206 @@
-use core::panic;
-use std::cmp::PartialEq;
-use std::collections::{HashMap, VecDeque};
-use std::{cmp::min, convert::Infallible, error::Error, net::SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{convert::Infallible, error::Error, net::SocketAddr};
 
-use env_logger::Env;
 use futures_util::{SinkExt, StreamExt};
-use log::{error, info};
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
-    sync::mpsc::{self, Sender},
-    time::sleep,
-};
-use warp::{
-    filters::ws::{Message, WebSocket},
-    reject::Rejection,
-    reply::Reply,
-    Filter,
+    sync::{broadcast::error::RecvError, mpsc},
+    time::timeout,
 };
+use tracing::{error, info, Instrument};
+use tracing_subscriber::EnvFilter;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use warp::{filters::ws::WebSocket, http::StatusCode, reject::Rejection, reply::Reply, Filter};
 
+use arrivals::ArrivalSchedule;
 use backend::BackendClient;
-use models::{Scenario, UpdateScenario, UpdateVehicle};
+use battery::{Batteries, BatteryModel};
+use control::{Command, CommandMessage};
+use dispatch::{Algorithm, BatchWindow};
+use emissions::EmissionFactors;
+use error::ClientError;
+use events::{Event, EventSender};
+use health::{Readiness, VersionInfo};
+use http::ClientConfig;
+use kpi::Kpis;
+use metrics::{GaugeGuard, METRICS};
+use models::Scenario;
+use recording::{Recorder, Replay};
 use runner::RunnerClient;
+use session::{
+    Broadcast, Published, Resume, RunInfo, RunSettings, RunState, Session, SessionRegistry,
+};
+use shifts::ShiftSchedule;
+use simulation::{replay_recording, scenario_simulator, Timetable};
 
+mod arrivals;
 mod backend;
+mod battery;
+mod cli;
+mod control;
+mod delta;
+mod dispatch;
+mod emissions;
+mod error;
+mod events;
+mod health;
+mod http;
+mod kpi;
 pub mod matching;
+mod metrics;
 mod models;
+mod openapi;
+mod rebalance;
+mod recording;
 mod runner;
+mod scenario_io;
+mod session;
+mod shifts;
+mod simulation;
 
-#[derive(Debug, serde::Deserialize)]
-enum Algorithm {
-    Nearest,
-    ALSN,
-}
-
-impl PartialEq for Algorithm {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Algorithm::Nearest, Algorithm::Nearest) => true,
-            (Algorithm::ALSN, Algorithm::ALSN) => true,
-            _ => false,
-        }
-    }
+#[derive(Debug, serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct WebSocketParams {
+    scenario_id: String,
+    speed: Option<f64>,
+    algorithm: Option<Algorithm>,
+    /// Write every tick of the run to the recordings directory
+    record: Option<bool>,
+    /// Customers arrive during the run, this many per minute in every zone of the area
+    arrival_rate: Option<f64>,
+    /// Customers arrive as listed in `<scenario_id>/arrivals.csv` in the inputs directory
+    arrival_list: Option<bool>,
+    /// Drivers work the shifts listed in `<scenario_id>/shifts.csv` in the inputs directory
+    shift_list: Option<bool>,
+    /// Electric vehicles charge at the chargers listed in `<scenario_id>/chargers.csv`
+    /// in the inputs directory
+    charger_list: Option<bool>,
+    /// Stream the recorded run of this scenario instead of contacting the runner
+    replay: Option<bool>,
+    /// Websocket protocol version, clients that don't set it get bare scenarios
+    protocol: Option<u32>,
+    /// Ticks between two full snapshots when deltas are sent
+    keyframe_interval: Option<u64>,
+    /// Join this session (e.g. someone else's replay) instead of the scenario's live run
+    session_id: Option<String>,
+    /// Resume the session after this message instead of starting with a snapshot
+    last_seq: Option<u64>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub(crate) struct WebSocketParams {
+/// How to run a scenario without a websocket client
+#[derive(Debug, serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct RunParams {
     scenario_id: String,
     speed: Option<f64>,
     algorithm: Option<Algorithm>,
+    /// Write every tick of the run to the recordings directory
+    record: Option<bool>,
+    /// Customers arrive during the run, this many per minute in every zone of the area
+    arrival_rate: Option<f64>,
+    /// Customers arrive as listed in `<scenario_id>/arrivals.csv` in the inputs directory
+    arrival_list: Option<bool>,
+    /// Drivers work the shifts listed in `<scenario_id>/shifts.csv` in the inputs directory
+    shift_list: Option<bool>,
+    /// Electric vehicles charge at the chargers listed in `<scenario_id>/chargers.csv`
+    /// in the inputs directory
+    charger_list: Option<bool>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
+pub(crate) struct RunDetails {
+    #[serde(flatten)]
+    run: RunInfo,
+    scenario: Scenario,
+    /// Final once the run is over, up to now while it's running
+    kpis: Kpis,
+}
+
+/// The outcome of a run that is over
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct RunSummary {
+    #[serde(flatten)]
+    run: RunInfo,
+    kpis: Kpis,
+}
+
+#[derive(Debug, serde::Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query, rename_all = "camelCase")]
 pub(crate) struct ScenarioCreationParams {
     number_of_vehicles: u64,
     number_of_customers: u64,
 }
 
-pub fn update_scenario_first(scenario: &Scenario) -> UpdateScenario {
-    // For each non-assigned vehicle, we assign the next available customer
-    let mut vehicle_assignments: Vec<UpdateVehicle> = Vec::new();
-
-    let (mut available_vehicles, unavailable_vehicles): (Vec<_>, Vec<_>) = scenario
-        .vehicles
-        .iter()
-        .partition(|vehicle| vehicle.customer_id.is_none());
-
-    let riding_customer_id_set = unavailable_vehicles
-        .iter()
-        .filter_map(|v| v.customer_id.clone())
-        .collect::<std::collections::HashSet<_>>();
-
-    for customer in scenario.customers.iter() {
-        if riding_customer_id_set.contains(&customer.id) || !customer.awaiting_service {
-            continue;
+/// Sends one message of the session's stream, `last_sent` is the last state the client got
+async fn send_published(
+    events: &EventSender,
+    published: &Published,
+    last_sent: &mut Arc<Scenario>,
+) -> Result<(), Box<dyn Error>> {
+    match &published.broadcast {
+        Broadcast::Tick(scenario) => {
+            events.send_tick(published.seq, last_sent, scenario).await?;
+            *last_sent = scenario.clone();
+            Ok(())
         }
-
-        let vehicle = available_vehicles.iter().min_by_key(|v| {
-            let dx = v.coord_x - customer.coord_x;
-            let dy = v.coord_y - customer.coord_y;
-            (dx * dx + dy * dy).abs() as u64
-        });
-        let Some(vehicle) = vehicle else {
-            break;
-        };
-
-        vehicle_assignments.push(UpdateVehicle {
-            id: vehicle.id.clone(),
-            customer_id: vehicle.customer_id.clone().unwrap_or(customer.id.clone()),
-        });
-        available_vehicles.swap_remove(
-            available_vehicles
-                .iter()
-                .position(|v| v.id == vehicle.id)
-                .unwrap(),
-        );
-    }
-
-    UpdateScenario {
-        vehicles: vehicle_assignments,
+        Broadcast::Event(event) => events.send_at(published.seq, (**event).clone()).await,
     }
 }
 
-pub(crate) async fn scenario_simulator(
-    runner_client: RunnerClient,
-    mut scenario: Scenario,
-    ws_sender: Sender<Message>,
-    speed: f64,
-    algorithm: Algorithm,
-) -> Result<(), Box<dyn Error>> {
-    let scenario_id = scenario.id.clone();
-
-    let mut precomputed_assignments: HashMap<String, VecDeque<String>> = HashMap::new();
-    if algorithm == Algorithm::ALSN {
-        precomputed_assignments = todo!(); //matching::compute_assignment(&scenario);
-    }
-    let scenario_launch = match runner_client.launch_scenario(&scenario_id, speed).await {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(format!("Failed to launch scenario: {}", e).into());
-        }
-    };
-
-    info!("Scenario launched: {:?}", scenario_launch);
-
-    while scenario.end_time.is_none() {
-        let assignments = match algorithm {
-            Algorithm::Nearest => update_scenario_first(&scenario),
-            Algorithm::ALSN => UpdateScenario {
-                vehicles: precomputed_assignments
-                    .iter()
-                    .filter_map(|(v, customer)| {
-                        customer.front().map(|c| UpdateVehicle {
-                            id: v.clone(),
-                            customer_id: c.clone(),
-                        })
-                    })
-                    .collect(),
-            },
-        };
-        debug_assert!(
-            assignments.vehicles.len() <= min(scenario.vehicles.len(), scenario.customers.len())
-        );
-        let update = runner_client
-            .update_scenario(&scenario_id, &assignments)
-            .await?;
-
-        if algorithm == Algorithm::ALSN {
-            for x in update.updated_vehicles {
-                precomputed_assignments.get_mut(&x.id).unwrap().pop_front();
+/// Catches a client up, returns the state it has now and the last message it got
+async fn resume_stream(
+    events: &EventSender,
+    resume: Resume,
+) -> Result<(Arc<Scenario>, u64), Box<dyn Error>> {
+    match resume {
+        Resume::Missed { mut base, missed } => {
+            // Deltas for the missed ticks build on the state the client had
+            let mut last = events.last_seq();
+            for published in &missed {
+                send_published(events, published, &mut base).await?;
+                last = published.seq;
             }
+            Ok((base, last))
         }
-
-        #[cfg(debug_assertions)]
-        if !update.failed_to_update.is_empty() {
-            // Dump all info to stdout before panicking
-            println!(
-                "Scenario: {}",
-                serde_json::to_string_pretty(&scenario).unwrap()
-            );
-            println!(
-                "Assignments: {}",
-                serde_json::to_string_pretty(&assignments).unwrap()
-            );
-            println!(
-                "Failed to Update (Vehicle IDs): {}",
-                serde_json::to_string_pretty(&update.failed_to_update).unwrap()
-            );
-
-            panic!("Wrong update sent for some vehicles!");
+        Resume::Snapshot {
+            seq,
+            scenario,
+            kpis,
+        } => {
+            events
+                .send_at(
+                    seq,
+                    Event::Snapshot {
+                        id: None,
+                        scenario: (*scenario).clone(),
+                    },
+                )
+                .await?;
+            if let Some(kpis) = kpis {
+                events.send(Event::Finished { kpis: *kpis }).await?;
+            }
+            Ok((scenario, seq))
         }
-
-        scenario = runner_client.get_scenario(&scenario_id).await?;
-
-        let Ok(_) = ws_sender.send((&scenario).try_into()?).await else {
-            return Err("WebSocket disconnected".into());
-        };
-
-        // TODO: Maybe not?
-        // sleep(std::time::Duration::from_millis(100)).await;
     }
-
-    Ok(())
 }
 
+/// How long a closing connection waits for the last messages of a cancelled run
+const FORWARDER_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub(crate) async fn handle_connection(
     ws: WebSocket,
-    initial_scenario: Scenario,
-    runner_client: RunnerClient,
-    params: WebSocketParams,
+    session: Arc<Session>,
+    protocol: u32,
+    keyframe_interval: u64,
+    last_seq: Option<u64>,
 ) {
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
     let (websocket_writer, mut websocket_outbound_stream) = mpsc::channel(1);
+    let events = EventSender::new(websocket_writer, protocol, keyframe_interval);
 
     // Every time we get a message from the outbound stream, send it to the user.
     tokio::spawn(async move {
@@ -206,38 +215,115 @@ pub(crate) async fn handle_connection(
         }
     });
 
-    // Initial message writing
-    let ws_writer_clone = websocket_writer.clone();
-    let initial_scenario_clone = initial_scenario.clone();
-    tokio::spawn(async move {
-        // TODO: some initial messages for setup
-        let _ = ws_writer_clone.send(
-            (&initial_scenario)
-                .try_into()
-                .expect("json serialization failed in initial write"),
-        );
+    let forwarder_events = events.clone();
+    let forwarder_session = session.clone();
+    let forwarder = tokio::spawn(async move {
+        let session = forwarder_session;
+        let events = forwarder_events;
 
-        scenario_simulator(
-            runner_client,
-            initial_scenario_clone,
-            ws_writer_clone,
-            params.speed.unwrap_or(0.033f64),
-            params.algorithm.unwrap_or(Algorithm::Nearest),
-        )
-        .await
-        .expect("Failed to run scenario simulation")
+        let (mut broadcasts, mut resume) = session.subscribe(last_seq);
+        let welcome_seq = match &resume {
+            Resume::Missed { .. } => last_seq.unwrap_or_default(),
+            Resume::Snapshot { .. } => 0,
+        };
+        let _ = events
+            .send(Event::Welcome {
+                session_id: session.id.clone(),
+                seq: welcome_seq,
+            })
+            .await;
+
+        loop {
+            let (mut last_sent, caught_up) = match resume_stream(&events, resume).await {
+                Ok(resumed) => resumed,
+                Err(_) => return,
+            };
+
+            // Every time the session publishes something, encode it for this client.
+            // Once the simulation is done, whatever is still buffered is sent before leaving.
+            loop {
+                let received = tokio::select! {
+                    biased;
+                    received = broadcasts.recv() => received,
+                    _ = session.done() => return,
+                };
+                let published = match received {
+                    Ok(published) => published,
+                    Err(RecvError::Lagged(skipped)) => {
+                        info!("WebSocket client missed {} messages, resuming", skipped);
+                        break;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if published.seq <= caught_up {
+                    continue;
+                }
+                if send_published(&events, &published, &mut last_sent)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+
+            (broadcasts, resume) = session.subscribe(Some(events.last_seq()));
+        }
     });
 
-    log::info!(
-        "Connected WebSocket connection for scenario id {}",
-        params.scenario_id,
+    info!(
+        "Connected WebSocket connection to session {} for scenario id {}",
+        session.id, session.scenario_id,
     );
 
-    // Every time we get a message from the user, handle it with the handler.
-    while let Some(result) = user_ws_rx.next().await {
+    let _viewer = session.join();
+    let _connection = GaugeGuard::new(&METRICS.websocket_connections);
+
+    // Every time we get a message from the user, hand it to the simulation
+    let mut cancelled = false;
+    loop {
+        let result = tokio::select! {
+            result = user_ws_rx.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            // The server is shutting down
+            _ = session.cancelled() => {
+                cancelled = true;
+                break;
+            }
+        };
+
         match result {
-            Ok(_message) => {
-                // TODO: Something
+            Ok(message) => {
+                let Ok(text) = message.to_str() else {
+                    continue;
+                };
+
+                let command = match serde_json::from_str::<CommandMessage>(text) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        let _ = events
+                            .send(Event::error(None, format!("Invalid command: {}", e)))
+                            .await;
+                        continue;
+                    }
+                };
+
+                // Keyframes are tracked per connection, other viewers keep their deltas
+                if let Command::Resync = command.command {
+                    events.request_resync();
+                    let _ = events.send(Event::ack(&command)).await;
+                    continue;
+                }
+
+                // Don't block reading while a manual assignment waits for the next update
+                let reply = session.command(command).await;
+                let events = events.clone();
+                tokio::spawn(async move {
+                    if let Ok(event) = reply.await {
+                        let _ = events.send(event).await;
+                    }
+                });
             }
             Err(e) => {
                 error!("Error receiving message from WebSocket: {}", e);
@@ -246,42 +332,723 @@ pub(crate) async fn handle_connection(
         }
     }
 
+    // The client is still there to be told why the run stopped
+    if cancelled {
+        let abort = forwarder.abort_handle();
+        if timeout(FORWARDER_DRAIN_TIMEOUT, forwarder).await.is_err() {
+            abort.abort();
+        }
+    } else {
+        forwarder.abort();
+    }
     info!(
-        "WebSocket connection closed for scenario id {}",
-        params.scenario_id,
+        "WebSocket connection closed for session {} of scenario id {}",
+        session.id, session.scenario_id,
     );
 }
 
-#[derive(Debug, Serialize, Clone)]
-struct ErrorMsg {
+/// The JSON body of every error response
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub(crate) struct ErrorMsg {
+    /// Stable identifier of the kind of error, for clients to match on
+    code: &'static str,
     message: String,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+impl ErrorMsg {
+    /// A failed call to the runner or the backend, answered with a matching status
+    fn client(context: &str, error: ClientError) -> Self {
+        ErrorMsg {
+            code: error.code(),
+            message: format!("{}: {}", context, error),
+            status: error.http_status(),
+        }
+    }
+
+    /// A scenario id that can't name a file
+    fn invalid_scenario_id(error: anyhow::Error) -> Self {
+        ErrorMsg {
+            code: "invalid_scenario_id",
+            message: error.to_string(),
+            status: StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 impl warp::reject::Reject for ErrorMsg {}
 
-pub(crate) async fn handle_ws_route(
-    params: WebSocketParams,
+/// Deserializes the query string, rejecting with the reason if it doesn't fit `T`
+fn query_params<T: DeserializeOwned + Send + 'static>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(|query: String| async move {
+            serde_urlencoded::from_str::<T>(&query).map_err(|e| {
+                warp::reject::custom(ErrorMsg {
+                    code: "invalid_query",
+                    message: format!("Invalid query string: {}", e),
+                    status: StatusCode::BAD_REQUEST,
+                })
+            })
+        })
+}
+
+/// Turns every rejection into a JSON [`ErrorMsg`] with a fitting status
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let error = if let Some(error) = rejection.find::<ErrorMsg>() {
+        error.clone()
+    } else {
+        let (status, code, message) = if rejection.is_not_found() {
+            (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+        } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+            (
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+                e.to_string(),
+            )
+        } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+            (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+        } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+            (StatusCode::BAD_REQUEST, "invalid_header", e.to_string())
+        } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+            (StatusCode::BAD_REQUEST, "invalid_header", e.to_string())
+        } else if let Some(e) = rejection.find::<warp::ws::MissingConnectionUpgrade>() {
+            (StatusCode::BAD_REQUEST, "websocket_required", e.to_string())
+        } else if let Some(e) = rejection.find::<warp::reject::LengthRequired>() {
+            (
+                StatusCode::LENGTH_REQUIRED,
+                "length_required",
+                e.to_string(),
+            )
+        } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+            (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                e.to_string(),
+            )
+        } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                e.to_string(),
+            )
+        } else {
+            error!("Unhandled rejection: {:?}", rejection);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Internal server error".to_string(),
+            )
+        };
+        ErrorMsg {
+            code,
+            message,
+            status,
+        }
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&error),
+        error.status,
+    ))
+}
+
+/// Starts a run of the scenario in the runner, the caller holds [`SessionRegistry::lock_start`]
+async fn start_run(
+    params: RunParams,
     runner_client: RunnerClient,
-    ws: warp::ws::Ws,
-) -> Result<impl Reply, Rejection> {
+    recordings_dir: &Path,
+    inputs_dir: &Path,
+    sessions: &SessionRegistry,
+    headless: bool,
+) -> Result<Arc<Session>, Rejection> {
+    // The run's clock is divided by the speed
+    let speed = params.speed.unwrap_or(0.033f64);
+    if !(speed.is_finite() && speed > 0.0) {
+        return Err(warp::reject::custom(ErrorMsg {
+            code: "invalid_query",
+            message: "speed must be a positive number".to_string(),
+            status: StatusCode::BAD_REQUEST,
+        }));
+    }
+
+    let recorder = if params.record.unwrap_or(false) {
+        let path = recording::recording_path(recordings_dir, &params.scenario_id)
+            .map_err(|e| warp::reject::custom(ErrorMsg::invalid_scenario_id(e)))?;
+        match Recorder::create(&path) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                let custom_error = ErrorMsg {
+                    code: "recording_failed",
+                    message: format!("Failed to create recording: {}", e),
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                return Err(warp::reject::custom(custom_error));
+            }
+        }
+    } else {
+        None
+    };
+
+    let invalid_arrivals = |message: String| {
+        warp::reject::custom(ErrorMsg {
+            code: "invalid_arrivals",
+            message,
+            status: StatusCode::BAD_REQUEST,
+        })
+    };
+    if params
+        .arrival_rate
+        .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+    {
+        return Err(invalid_arrivals(
+            "arrival_rate must be a positive number".to_string(),
+        ));
+    }
+    let arrival_list = match params.arrival_list {
+        Some(true) if params.arrival_rate.is_some() => {
+            return Err(invalid_arrivals(
+                "Use either arrival_rate or arrival_list".to_string(),
+            ));
+        }
+        Some(true) => {
+            let path = arrivals::arrival_list_path(inputs_dir, &params.scenario_id)
+                .map_err(|e| warp::reject::custom(ErrorMsg::invalid_scenario_id(e)))?;
+            match ArrivalSchedule::load(&path) {
+                Ok(list) => Some(list),
+                Err(e) => return Err(invalid_arrivals(format!("{:#}", e))),
+            }
+        }
+        _ => None,
+    };
+
+    let shifts = if params.shift_list.unwrap_or(false) {
+        let path = shifts::shift_list_path(inputs_dir, &params.scenario_id)
+            .map_err(|e| warp::reject::custom(ErrorMsg::invalid_scenario_id(e)))?;
+        match ShiftSchedule::load(&path) {
+            Ok(shifts) => shifts,
+            Err(e) => {
+                return Err(warp::reject::custom(ErrorMsg {
+                    code: "invalid_shifts",
+                    message: format!("{:#}", e),
+                    status: StatusCode::BAD_REQUEST,
+                }));
+            }
+        }
+    } else {
+        ShiftSchedule::default()
+    };
+
+    let chargers = if params.charger_list.unwrap_or(false) {
+        let path = battery::charger_list_path(inputs_dir, &params.scenario_id)
+            .map_err(|e| warp::reject::custom(ErrorMsg::invalid_scenario_id(e)))?;
+        match battery::load_chargers(&path) {
+            Ok(chargers) => chargers,
+            Err(e) => {
+                return Err(warp::reject::custom(ErrorMsg {
+                    code: "invalid_chargers",
+                    message: format!("{:#}", e),
+                    status: StatusCode::BAD_REQUEST,
+                }));
+            }
+        }
+    } else {
+        vec![]
+    };
+
     // Import the scenario from the database into the scenario runner simulation
     let initial_scenario = match runner_client.initialize_scenario(&params.scenario_id).await {
         Ok(s) => s,
         Err(e) => {
-            let custom_error = ErrorMsg {
-                message: format!("Failed to initialize scenario: {}", e),
-            };
+            let custom_error = ErrorMsg::client("Failed to initialize scenario", e);
             return Err(warp::reject::custom(custom_error));
         }
     };
 
-    let response = ws.on_upgrade(move |socket| {
-        handle_connection(socket, initial_scenario, runner_client, params)
+    let arrivals = match (arrival_list, params.arrival_rate) {
+        (Some(list), _) => list,
+        (None, Some(rate)) => {
+            ArrivalSchedule::poisson(&initial_scenario, rate, &mut rand::thread_rng())
+        }
+        (None, None) => ArrivalSchedule::default(),
+    };
+    let batteries = Batteries::new(
+        &initial_scenario,
+        sessions.emissions(),
+        sessions.battery_model(),
+    );
+    let mut timetable = Timetable {
+        arrivals,
+        shifts,
+        chargers,
+        batteries,
+    };
+    let initial_scenario = timetable.apply(initial_scenario, Duration::ZERO);
+
+    let algorithm = params.algorithm.unwrap_or(Algorithm::Nearest);
+    let settings = RunSettings {
+        speed,
+        algorithm,
+        record: recorder.is_some(),
+        arrival_rate: params.arrival_rate,
+        arrival_list: params.arrival_list.unwrap_or(false),
+        shift_list: params.shift_list.unwrap_or(false),
+        charger_list: params.charger_list.unwrap_or(false),
+    };
+    let batch_window = sessions.batch_window();
+    let session = sessions.start(initial_scenario, false, {
+        let settings = settings.clone();
+        move |session, commands| async move {
+            // Nobody watches headless runs, they must not be stopped for being idle
+            let _headless = headless.then(|| session.join());
+            scenario_simulator(
+                runner_client,
+                session,
+                commands,
+                settings,
+                batch_window,
+                recorder,
+                timetable,
+            )
+            .await
+        }
     });
+    session.set_settings(settings);
+    Ok(session)
+}
+
+/// What a viewer asked for that differs from how the live run it joins was started
+fn conflicting_settings(params: &WebSocketParams, settings: &RunSettings) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    if params.speed.is_some_and(|speed| speed != settings.speed) {
+        conflicts.push(format!("speed is {}", settings.speed));
+    }
+    if params
+        .algorithm
+        .is_some_and(|algorithm| algorithm != settings.algorithm)
+    {
+        conflicts.push(format!("algorithm is {:?}", settings.algorithm));
+    }
+    if params
+        .record
+        .is_some_and(|record| record != settings.record)
+    {
+        conflicts.push(format!("record is {}", settings.record));
+    }
+    if params.arrival_rate.is_some() && params.arrival_rate != settings.arrival_rate {
+        match settings.arrival_rate {
+            Some(rate) => conflicts.push(format!("arrival_rate is {}", rate)),
+            None => conflicts.push("arrival_rate is not set".to_string()),
+        }
+    }
+    let lists = [
+        ("arrival_list", params.arrival_list, settings.arrival_list),
+        ("shift_list", params.shift_list, settings.shift_list),
+        ("charger_list", params.charger_list, settings.charger_list),
+    ];
+    for (name, requested, actual) in lists {
+        if requested.is_some_and(|requested| requested != actual) {
+            conflicts.push(format!("{} is {}", name, actual));
+        }
+    }
+    conflicts
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws",
+    params(WebSocketParams),
+    responses(
+        (status = 101, description = "Upgraded to a websocket. Clients send `CommandMessage`s. \
+            The server sends a bare `Scenario` per tick to protocol 1 clients, \
+            and `Envelope`s to protocol 2 and 3 clients"),
+        (status = 400, description = "Invalid query string", body = ErrorMsg),
+        (status = 404, description = "Unknown session or recording", body = ErrorMsg),
+        (status = 409, description = "The scenario is already running with other settings", body = ErrorMsg),
+        (status = 502, description = "The runner failed to initialize the scenario", body = ErrorMsg),
+    ),
+    tag = "runs"
+)]
+pub(crate) async fn handle_ws_route(
+    params: WebSocketParams,
+    runner_client: RunnerClient,
+    recordings_dir: PathBuf,
+    inputs_dir: PathBuf,
+    sessions: SessionRegistry,
+    ws: warp::ws::Ws,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let protocol = params.protocol.unwrap_or(events::LEGACY_PROTOCOL);
+    let keyframe_interval = params
+        .keyframe_interval
+        .unwrap_or(delta::DEFAULT_KEYFRAME_INTERVAL);
+
+    let last_seq = params.last_seq.filter(|_| params.session_id.is_some());
+
+    let session = if let Some(session_id) = &params.session_id {
+        match sessions.get(session_id) {
+            Some(session) => session,
+            None => {
+                let custom_error = ErrorMsg {
+                    code: "session_not_found",
+                    message: format!("There is no session {}", session_id),
+                    status: StatusCode::NOT_FOUND,
+                };
+                return Err(warp::reject::custom(custom_error));
+            }
+        }
+    } else if params.replay.unwrap_or(false) {
+        let recording_path = recording::recording_path(&recordings_dir, &params.scenario_id)
+            .map_err(|e| warp::reject::custom(ErrorMsg::invalid_scenario_id(e)))?;
+        let mut replay = match Replay::open(&recording_path) {
+            Ok(r) => r,
+            Err(e) => {
+                let custom_error = ErrorMsg {
+                    code: "recording_not_found",
+                    message: format!("Failed to open recording: {}", e),
+                    status: StatusCode::NOT_FOUND,
+                };
+                return Err(warp::reject::custom(custom_error));
+            }
+        };
+
+        let initial_scenario = match replay.peek_scenario() {
+            Ok(s) => s,
+            Err(e) => {
+                let custom_error = ErrorMsg {
+                    code: "invalid_recording",
+                    message: format!("Failed to read recording: {}", e),
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                return Err(warp::reject::custom(custom_error));
+            }
+        };
+
+        // Every replay gets its own session, so viewers can pause it independently
+        sessions.start(initial_scenario, true, move |session, commands| {
+            replay_recording(replay, session, commands)
+        })
+    } else {
+        let _starting = sessions.lock_start(&params.scenario_id).await;
+
+        match sessions.find_live(&params.scenario_id) {
+            Some(session) => {
+                let conflicts = session
+                    .settings()
+                    .map(|settings| conflicting_settings(&params, &settings))
+                    .unwrap_or_default();
+                if !conflicts.is_empty() {
+                    let custom_error = ErrorMsg {
+                        code: "run_settings_conflict",
+                        message: format!(
+                            "Scenario {} is already running with other settings ({}), \
+                            leave them out to watch it",
+                            session.scenario_id,
+                            conflicts.join(", ")
+                        ),
+                        status: StatusCode::CONFLICT,
+                    };
+                    return Err(warp::reject::custom(custom_error));
+                }
+
+                info!(
+                    "Joining running session {} of scenario {}",
+                    session.id, session.scenario_id
+                );
+                session
+            }
+            None => {
+                let run = RunParams {
+                    scenario_id: params.scenario_id.clone(),
+                    speed: params.speed,
+                    algorithm: params.algorithm,
+                    record: params.record,
+                    arrival_rate: params.arrival_rate,
+                    arrival_list: params.arrival_list,
+                    shift_list: params.shift_list,
+                    charger_list: params.charger_list,
+                };
+                start_run(
+                    run,
+                    runner_client,
+                    &recordings_dir,
+                    &inputs_dir,
+                    &sessions,
+                    false,
+                )
+                .await?
+            }
+        }
+    };
+
+    Ok(Box::new(ws.on_upgrade(move |socket| {
+        let span = session.span();
+        handle_connection(socket, session, protocol, keyframe_interval, last_seq).instrument(span)
+    })))
+}
 
-    Ok(response)
+fn find_run(sessions: &SessionRegistry, run_id: &str) -> Result<Arc<Session>, Rejection> {
+    sessions.get(run_id).ok_or_else(|| {
+        warp::reject::custom(ErrorMsg {
+            code: "run_not_found",
+            message: format!("There is no run {}", run_id),
+            status: StatusCode::NOT_FOUND,
+        })
+    })
 }
 
+#[utoipa::path(
+    get,
+    path = "/runs",
+    responses((status = 200, description = "All runs, oldest first", body = [RunInfo])),
+    tag = "runs"
+)]
+pub(crate) async fn list_runs(sessions: SessionRegistry) -> Result<impl Reply, Rejection> {
+    let runs: Vec<RunInfo> = sessions.list().iter().map(|s| s.info()).collect();
+    Ok(warp::reply::json(&runs))
+}
+
+/// Starts a headless run, it keeps going until it's over or cancelled
+#[utoipa::path(
+    post,
+    path = "/runs",
+    params(RunParams),
+    responses(
+        (status = 201, description = "The run was started", body = RunInfo),
+        (status = 400, description = "Invalid query string", body = ErrorMsg),
+        (status = 409, description = "The scenario is already running", body = ErrorMsg),
+        (status = 502, description = "The runner failed to initialize the scenario", body = ErrorMsg),
+    ),
+    tag = "runs"
+)]
+pub(crate) async fn create_run(
+    params: RunParams,
+    runner_client: RunnerClient,
+    recordings_dir: PathBuf,
+    inputs_dir: PathBuf,
+    sessions: SessionRegistry,
+) -> Result<impl Reply, Rejection> {
+    let _starting = sessions.lock_start(&params.scenario_id).await;
+
+    if let Some(session) = sessions.find_live(&params.scenario_id) {
+        let custom_error = ErrorMsg {
+            code: "run_exists",
+            message: format!(
+                "Scenario {} is already running as {}",
+                session.scenario_id, session.id
+            ),
+            status: StatusCode::CONFLICT,
+        };
+        return Err(warp::reject::custom(custom_error));
+    }
+
+    let session = start_run(
+        params,
+        runner_client,
+        &recordings_dir,
+        &inputs_dir,
+        &sessions,
+        true,
+    )
+    .await?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&session.info()),
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/runs/{run_id}",
+    params(("run_id" = String, Path, description = "Session id of the run")),
+    responses(
+        (status = 200, description = "The run with its current scenario", body = RunDetails),
+        (status = 404, description = "Unknown run", body = ErrorMsg),
+    ),
+    tag = "runs"
+)]
+pub(crate) async fn get_run(
+    run_id: String,
+    sessions: SessionRegistry,
+) -> Result<impl Reply, Rejection> {
+    let session = find_run(&sessions, &run_id)?;
+    let scenario = session.latest();
+
+    Ok(warp::reply::json(&RunDetails {
+        run: session.info(),
+        kpis: session.kpis(),
+        scenario: (*scenario).clone(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/runs/{run_id}/summary",
+    params(("run_id" = String, Path, description = "Session id of the run")),
+    responses(
+        (status = 200, description = "The outcome of the run", body = RunSummary),
+        (status = 404, description = "Unknown run", body = ErrorMsg),
+        (status = 409, description = "The run is still in progress", body = ErrorMsg),
+    ),
+    tag = "runs"
+)]
+pub(crate) async fn get_run_summary(
+    run_id: String,
+    sessions: SessionRegistry,
+) -> Result<impl Reply, Rejection> {
+    let session = find_run(&sessions, &run_id)?;
+
+    if session.state() == RunState::Running {
+        let custom_error = ErrorMsg {
+            code: "run_in_progress",
+            message: format!("Run {} is still in progress", run_id),
+            status: StatusCode::CONFLICT,
+        };
+        return Err(warp::reject::custom(custom_error));
+    }
+
+    Ok(warp::reply::json(&RunSummary {
+        run: session.info(),
+        kpis: session.kpis(),
+    }))
+}
+
+/// Pauses, resumes or cancels a run
+#[utoipa::path(
+    post,
+    path = "/runs/{run_id}/{action}",
+    params(
+        ("run_id" = String, Path, description = "Session id of the run"),
+        ("action" = String, Path, description = "`pause`, `resume` or `cancel`"),
+    ),
+    responses(
+        (status = 200, description = "The run was paused or resumed", body = RunInfo),
+        (status = 202, description = "The run is being cancelled", body = RunInfo),
+        (status = 404, description = "Unknown run or action", body = ErrorMsg),
+        (status = 409, description = "The run is over or rejected the command", body = ErrorMsg),
+    ),
+    tag = "runs"
+)]
+pub(crate) async fn control_run(
+    run_id: String,
+    action: String,
+    sessions: SessionRegistry,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let session = find_run(&sessions, &run_id)?;
+
+    let not_running = || {
+        warp::reject::custom(ErrorMsg {
+            code: "run_not_running",
+            message: format!("Run {} is not running anymore", run_id),
+            status: StatusCode::CONFLICT,
+        })
+    };
+    if session.state() != RunState::Running {
+        return Err(not_running());
+    }
+
+    let command = match action.as_str() {
+        "pause" => Command::Pause,
+        "resume" => Command::Resume,
+        "cancel" => {
+            session.cancel();
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&session.info()),
+                StatusCode::ACCEPTED,
+            )));
+        }
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    let reply = session
+        .command(CommandMessage { id: None, command })
+        .await
+        .await;
+    match reply {
+        Ok(Event::Error { message, .. }) => Err(warp::reject::custom(ErrorMsg {
+            code: "command_rejected",
+            message,
+            status: StatusCode::CONFLICT,
+        })),
+        Ok(_) => Ok(Box::new(warp::reply::json(&session.info()))),
+        Err(_) => Err(not_running()),
+    }
+}
+
+/// The process is alive, used by docker-compose
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses((status = 200, description = "The server is running")),
+    tag = "health"
+)]
+pub(crate) async fn healthz() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&serde_json::json!({ "status": "ok" })))
+}
+
+/// The runner and the backend answer, so runs can be started
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Both services answer", body = Readiness),
+        (status = 503, description = "At least one service doesn't", body = Readiness),
+    ),
+    tag = "health"
+)]
+pub(crate) async fn readyz(
+    runner_client: RunnerClient,
+    backend_client: BackendClient,
+) -> Result<impl Reply, Rejection> {
+    let readiness = Readiness::check(&runner_client, &backend_client).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses((status = 200, description = "What is running", body = VersionInfo)),
+    tag = "health"
+)]
+pub(crate) async fn version() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&VersionInfo::current()))
+}
+
+/// Latencies, tick durations and counters in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Prometheus metrics", content_type = "text/plain")),
+    tag = "health"
+)]
+pub(crate) async fn metrics() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        metrics::render(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/scenario/create",
+    params(ScenarioCreationParams),
+    responses(
+        (status = 200, description = "The new scenario", body = Scenario),
+        (status = 502, description = "The backend failed to create it", body = ErrorMsg),
+    ),
+    tag = "scenarios"
+)]
 pub(crate) async fn create_scenario(
     params: ScenarioCreationParams,
     backend_client: BackendClient,
@@ -292,30 +1059,211 @@ pub(crate) async fn create_scenario(
 
     match response {
         Ok(scenario) => Ok(warp::reply::json(&scenario)),
+        Err(e) => {
+            let custom_error = ErrorMsg::client("Failed to create scenario", e);
+            Err(warp::reject::custom(custom_error))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/scenario/{scenario_id}/export/{file}",
+    params(
+        ("scenario_id" = String, Path),
+        ("file" = String, Path, description = "`scenario.json`, `scenario.geojson`, `vehicles.csv` or `customers.csv`"),
+    ),
+    responses(
+        (status = 200, description = "The scenario in the requested format"),
+        (status = 404, description = "Unknown scenario or file", body = ErrorMsg),
+    ),
+    tag = "scenarios"
+)]
+pub(crate) async fn export_scenario(
+    scenario_id: String,
+    file: String,
+    backend_client: BackendClient,
+) -> Result<impl Reply, Rejection> {
+    let scenario = match backend_client.get_scenario(&scenario_id).await {
+        Ok(s) => s,
+        Err(e) => {
+            let custom_error = ErrorMsg::client("Failed to fetch scenario", e);
+            return Err(warp::reject::custom(custom_error));
+        }
+    };
+
+    let (content_type, body) = match file.as_str() {
+        "scenario.json" => ("application/json", serde_json::to_string(&scenario).ok()),
+        "scenario.geojson" => (
+            "application/geo+json",
+            serde_json::to_string(&scenario_io::to_geojson(&scenario)).ok(),
+        ),
+        "vehicles.csv" => (
+            "text/csv",
+            scenario_io::vehicles_to_csv(&scenario.vehicles).ok(),
+        ),
+        "customers.csv" => (
+            "text/csv",
+            scenario_io::customers_to_csv(&scenario.customers).ok(),
+        ),
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    let Some(body) = body else {
+        let custom_error = ErrorMsg {
+            code: "export_failed",
+            message: format!("Failed to export scenario as {}", file),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return Err(warp::reject::custom(custom_error));
+    };
+
+    Ok(warp::reply::with_header(body, "Content-Type", content_type))
+}
+
+#[utoipa::path(
+    post,
+    path = "/scenario/import/{file}",
+    params(("file" = String, Path, description = "`scenario.json` or `scenario.geojson`")),
+    request_body(content = String, description = "The file's content"),
+    responses(
+        (status = 200, description = "The parsed scenario", body = Scenario),
+        (status = 400, description = "The file could not be parsed", body = ErrorMsg),
+    ),
+    tag = "scenarios"
+)]
+pub(crate) async fn import_scenario(
+    file: String,
+    body: warp::hyper::body::Bytes,
+) -> Result<impl Reply, Rejection> {
+    let scenario = match file.as_str() {
+        "scenario.json" => serde_json::from_slice(&body).map_err(anyhow::Error::from),
+        "scenario.geojson" => serde_json::from_slice(&body)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| scenario_io::from_geojson(&value)),
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    match scenario {
+        Ok(scenario) => Ok(warp::reply::json::<Scenario>(&scenario)),
         Err(e) => {
             let custom_error = ErrorMsg {
-                message: format!("Failed to create scenario: {}", e),
+                code: "invalid_scenario",
+                message: format!("Failed to import scenario: {}", e),
+                status: StatusCode::BAD_REQUEST,
             };
             Err(warp::reject::custom(custom_error))
         }
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/scenario/import",
+    request_body(
+        content = String,
+        content_type = "multipart/form-data",
+        description = "`vehicles.csv` and `customers.csv` parts, optionally `chargers.csv` and an `id`"
+    ),
+    responses(
+        (status = 200, description = "The parsed scenario", body = Scenario),
+        (status = 400, description = "A part is missing or could not be parsed", body = ErrorMsg),
+    ),
+    tag = "scenarios"
+)]
+pub(crate) async fn import_csv_scenario(
+    form: warp::multipart::FormData,
+) -> Result<impl Reply, Rejection> {
+    let invalid = |message: String| {
+        warp::reject::custom(ErrorMsg {
+            code: "invalid_scenario",
+            message,
+            status: StatusCode::BAD_REQUEST,
+        })
+    };
+
+    let mut parts = std::collections::HashMap::new();
+    let mut form = std::pin::pin!(form);
+    while let Some(part) = form.next().await {
+        let part = part.map_err(|e| invalid(format!("Failed to read the form: {}", e)))?;
+        let name = part.name().to_string();
+        let mut content = vec![];
+        let mut data = std::pin::pin!(part.stream());
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk.map_err(|e| invalid(format!("Failed to read {}: {}", name, e)))?;
+            content.extend_from_slice(warp::hyper::body::Buf::chunk(&chunk));
+        }
+        let content = String::from_utf8(content)
+            .map_err(|_| invalid(format!("{} is not valid UTF-8", name)))?;
+        parts.insert(name, content);
+    }
+
+    let part = |name: &str| {
+        parts
+            .get(name)
+            .ok_or_else(|| invalid(format!("The form has no {} part", name)))
+    };
+    let scenario = scenario_io::from_csv_files(
+        parts.get("id").cloned().unwrap_or_default(),
+        part(scenario_io::VEHICLES_CSV)?,
+        part(scenario_io::CUSTOMERS_CSV)?,
+        parts.get(scenario_io::CHARGERS_CSV).map(String::as_str),
+    )
+    .map_err(|e| invalid(format!("Failed to import scenario: {}", e)))?;
+
+    Ok(warp::reply::json::<Scenario>(&scenario))
+}
+
 fn with_runner_client(
     client: RunnerClient,
 ) -> impl Filter<Extract = (RunnerClient,), Error = Infallible> + Clone {
     warp::any().map(move || client.clone())
 }
 
+fn with_recordings_dir(
+    recordings_dir: PathBuf,
+) -> impl Filter<Extract = (PathBuf,), Error = Infallible> + Clone {
+    warp::any().map(move || recordings_dir.clone())
+}
+
+fn with_inputs_dir(
+    inputs_dir: PathBuf,
+) -> impl Filter<Extract = (PathBuf,), Error = Infallible> + Clone {
+    warp::any().map(move || inputs_dir.clone())
+}
+
+fn with_session_registry(
+    sessions: SessionRegistry,
+) -> impl Filter<Extract = (SessionRegistry,), Error = Infallible> + Clone {
+    warp::any().map(move || sessions.clone())
+}
+
 fn with_backend_client(
     client: BackendClient,
 ) -> impl Filter<Extract = (BackendClient,), Error = Infallible> + Clone {
     warp::any().map(move || client.clone())
 }
 
+/// Logs to stderr, as JSON lines if `LOG_FORMAT=json`. `RUST_LOG` sets the levels.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+        _ => subscriber.init(),
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    init_tracing();
 
     let web_server_port: u16 = std::env::var("PORT")
         .unwrap_or("5000".to_string())
@@ -324,30 +1272,365 @@ async fn main() {
 
     let runner_base_url =
         std::env::var("RUNNER_BASE_URL").unwrap_or("http://localhost:8090".to_string());
-    let runner_client = RunnerClient::new(&runner_base_url);
+    let runner_client = RunnerClient::new(&runner_base_url, ClientConfig::from_env("RUNNER"));
 
     let backend_base_url =
         std::env::var("BACKEND_BASE_URL").unwrap_or("http://localhost:8080".to_string());
-    let backend_client = BackendClient::new(&backend_base_url);
+    let backend_client = BackendClient::new(&backend_base_url, ClientConfig::from_env("BACKEND"));
+
+    let recordings_dir =
+        PathBuf::from(std::env::var("RECORDINGS_DIR").unwrap_or("recordings".to_string()));
+    // Files that go with a scenario, such as arrival lists, one directory per scenario id
+    let inputs_dir = PathBuf::from(std::env::var("INPUTS_DIR").unwrap_or("inputs".to_string()));
+
+    let sessions = SessionRegistry::new(
+        EmissionFactors::from_env(),
+        BatchWindow::from_env(),
+        BatteryModel::from_env(),
+    );
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args, &backend_client).await {
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let create_scenario_route = warp::path!("scenario" / "create")
         .and(warp::post())
-        .and(warp::query::<ScenarioCreationParams>())
-        .and(with_backend_client(backend_client))
+        .and(query_params::<ScenarioCreationParams>())
+        .and(with_backend_client(backend_client.clone()))
         .and_then(create_scenario);
 
+    let export_scenario_route = warp::path!("scenario" / String / "export" / String)
+        .and(warp::get())
+        .and(with_backend_client(backend_client.clone()))
+        .and_then(export_scenario);
+
+    let import_scenario_route = warp::path!("scenario" / "import" / String)
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and_then(import_scenario);
+
+    let import_csv_scenario_route = warp::path!("scenario" / "import")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(16 * 1024 * 1024))
+        .and_then(import_csv_scenario);
+
+    let list_runs_route = warp::path!("runs")
+        .and(warp::get())
+        .and(with_session_registry(sessions.clone()))
+        .and_then(list_runs);
+
+    let create_run_route = warp::path!("runs")
+        .and(warp::post())
+        .and(query_params::<RunParams>())
+        .and(with_runner_client(runner_client.clone()))
+        .and(with_recordings_dir(recordings_dir.clone()))
+        .and(with_inputs_dir(inputs_dir.clone()))
+        .and(with_session_registry(sessions.clone()))
+        .and_then(create_run);
+
+    let get_run_route = warp::path!("runs" / String)
+        .and(warp::get())
+        .and(with_session_registry(sessions.clone()))
+        .and_then(get_run);
+
+    let run_summary_route = warp::path!("runs" / String / "summary")
+        .and(warp::get())
+        .and(with_session_registry(sessions.clone()))
+        .and_then(get_run_summary);
+
+    let control_run_route = warp::path!("runs" / String / String)
+        .and(warp::post())
+        .and(with_session_registry(sessions.clone()))
+        .and_then(control_run);
+
+    let healthz_route = warp::path!("healthz").and(warp::get()).and_then(healthz);
+
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_runner_client(runner_client.clone()))
+        .and(with_backend_client(backend_client.clone()))
+        .and_then(readyz);
+
+    let version_route = warp::path!("version").and(warp::get()).and_then(version);
+
+    let metrics_route = warp::path!("metrics").and(warp::get()).and_then(metrics);
+
+    let openapi_route = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&openapi::ApiDoc::openapi()));
+
     let ws_route = warp::path("ws")
-        .and(warp::query::<WebSocketParams>())
+        .and(query_params::<WebSocketParams>())
         .and(with_runner_client(runner_client))
+        .and(with_recordings_dir(recordings_dir))
+        .and(with_inputs_dir(inputs_dir))
+        .and(with_session_registry(sessions.clone()))
         .and(warp::ws().map(|ws: warp::ws::Ws| ws.max_frame_size(64 << 20)))
         .and_then(handle_ws_route);
 
-    let routes = ws_route.or(create_scenario_route);
+    let routes = ws_route
+        .or(create_scenario_route)
+        .or(export_scenario_route)
+        .or(import_scenario_route)
+        .or(import_csv_scenario_route)
+        .or(list_runs_route)
+        .or(create_run_route)
+        .or(get_run_route)
+        .or(run_summary_route)
+        .or(control_run_route)
+        .or(openapi_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(version_route)
+        .or(metrics_route)
+        .recover(handle_rejection);
 
     let addr: SocketAddr = ("[::]:".to_owned() + web_server_port.to_string().as_str())
         .parse()
         .unwrap();
 
     info!("Starting web server on port {}", web_server_port);
-    warp::serve(routes).run(addr).await;
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, async move {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Shutting down, stopping all simulations");
+        sessions.shutdown().await;
+    });
+    server.await;
+}
+
+/*=================TESTS===============================*/
+
+#[tokio::test]
+async fn test_rejections_are_json_errors() {
+    let routes = warp::path("ws")
+        .and(warp::get())
+        .and(query_params::<WebSocketParams>())
+        .map(|_| "ok")
+        .recover(handle_rejection);
+
+    let response = warp::test::request()
+        .path("/ws?scenario_id=s1&algorithm=Fastest")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["code"], "invalid_query");
+    assert!(body["message"].as_str().unwrap().contains("Fastest"));
+
+    let response = warp::test::request().path("/ws").reply(&routes).await;
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert!(body["message"].as_str().unwrap().contains("scenario_id"));
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/ws?scenario_id=s1")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    let response = warp::test::request().path("/nope").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_run_endpoints() {
+    let sessions = SessionRegistry::default();
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        ..Default::default()
+    };
+    let session = sessions.start(scenario, false, |_session, _commands| async {
+        std::future::pending::<()>().await;
+        Ok(())
+    });
+
+    let routes = warp::path!("runs")
+        .and(with_session_registry(sessions.clone()))
+        .and_then(list_runs)
+        .or(warp::path!("runs" / String)
+            .and(with_session_registry(sessions.clone()))
+            .and_then(get_run))
+        .or(warp::path!("runs" / String / "summary")
+            .and(with_session_registry(sessions.clone()))
+            .and_then(get_run_summary))
+        .or(warp::path!("runs" / String / String)
+            .and(warp::post())
+            .and(with_session_registry(sessions.clone()))
+            .and_then(control_run))
+        .recover(handle_rejection);
+
+    let response = warp::test::request().path("/runs").reply(&routes).await;
+    let runs: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(runs[0]["id"], session.id);
+    assert_eq!(runs[0]["state"], "running");
+
+    let path = format!("/runs/{}", session.id);
+    let response = warp::test::request().path(&path).reply(&routes).await;
+    let run: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(run["scenario"]["id"], "s1");
+    assert_eq!(run["kpis"]["vehicles"], 0);
+
+    let summary = format!("/runs/{}/summary", session.id);
+    let response = warp::test::request().path(&summary).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/runs/{}/cancel", session.id))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    while session.state() == RunState::Running {
+        tokio::task::yield_now().await;
+    }
+    let response = warp::test::request().path(&summary).reply(&routes).await;
+    let run: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(run["state"], "cancelled");
+
+    let response = warp::test::request()
+        .path("/runs/nope")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_runs_need_a_positive_speed() {
+    let runner_client = RunnerClient::new("http://127.0.0.1:1", ClientConfig::default());
+    let routes = warp::path!("runs")
+        .and(warp::post())
+        .and(query_params::<RunParams>())
+        .and(with_runner_client(runner_client))
+        .and(with_recordings_dir(PathBuf::from("recordings")))
+        .and(with_inputs_dir(PathBuf::from("inputs")))
+        .and(with_session_registry(SessionRegistry::default()))
+        .and_then(create_run)
+        .recover(handle_rejection);
+
+    for speed in ["0", "-1", "NaN", "inf"] {
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/runs?scenario_id=s1&speed={}", speed))
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            response.status(),
+            StatusCode::BAD_REQUEST,
+            "speed {}",
+            speed
+        );
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["code"], "invalid_query");
+    }
+}
+
+#[test]
+fn test_viewers_cannot_change_a_running_scenario() {
+    let settings = RunSettings {
+        speed: 0.5,
+        algorithm: Algorithm::Nearest,
+        record: false,
+        arrival_rate: None,
+        arrival_list: false,
+        shift_list: true,
+        charger_list: false,
+    };
+    let params = |query: &str| -> WebSocketParams { serde_urlencoded::from_str(query).unwrap() };
+
+    let same = params("scenario_id=s1&speed=0.5&shift_list=true");
+    assert!(conflicting_settings(&same, &settings).is_empty());
+    let defaults = params("scenario_id=s1");
+    assert!(conflicting_settings(&defaults, &settings).is_empty());
+
+    let other = params("scenario_id=s1&speed=1&algorithm=Batched&arrival_rate=2&shift_list=false");
+    assert_eq!(
+        conflicting_settings(&other, &settings),
+        [
+            "speed is 0.5",
+            "algorithm is Nearest",
+            "arrival_rate is not set",
+            "shift_list is true"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_readiness_reports_unreachable_services() {
+    // Nothing listens on port 1, so the connection is refused right away
+    let runner_client = RunnerClient::new("http://127.0.0.1:1", ClientConfig::default());
+    let backend_client = BackendClient::new("http://127.0.0.1:1", ClientConfig::default());
+    let route = warp::path!("readyz")
+        .and(with_runner_client(runner_client))
+        .and(with_backend_client(backend_client))
+        .and_then(readyz);
+
+    let response = warp::test::request().path("/readyz").reply(&route).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["runner"]["ok"], false);
+    assert!(body["backend"]["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_csv_scenario_import() {
+    let routes = warp::path!("scenario" / "import")
+        .and(warp::post())
+        .and(warp::multipart::form())
+        .and_then(import_csv_scenario)
+        .recover(handle_rejection);
+
+    let scenario = scenario_io::sample_scenario();
+    let form = |parts: &[(&str, String)]| {
+        let mut body = String::new();
+        for (name, content) in parts {
+            body += &format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, content
+            );
+        }
+        body + "--boundary--\r\n"
+    };
+    let request = |body: String| {
+        warp::test::request()
+            .method("POST")
+            .path("/scenario/import")
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .body(body)
+    };
+
+    let body = form(&[
+        ("id", "s1".to_string()),
+        (
+            "vehicles.csv",
+            scenario_io::vehicles_to_csv(&scenario.vehicles).unwrap(),
+        ),
+        (
+            "customers.csv",
+            scenario_io::customers_to_csv(&scenario.customers).unwrap(),
+        ),
+    ]);
+    let response = request(body).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let imported: Scenario = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(imported.id, "s1");
+    assert_eq!(imported.vehicles[0].customer_id.as_deref(), Some("c1"));
+    assert_eq!(imported.customers.len(), 2);
+    assert_eq!(imported.customers[0].destination_x, Some(48.15));
+
+    let body = form(&[(
+        "vehicles.csv",
+        scenario_io::vehicles_to_csv(&scenario.vehicles).unwrap(),
+    )]);
+    let response = request(body).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert!(body["message"].as_str().unwrap().contains("customers.csv"));
 }
@@ -0,0 +1,99 @@
+/// Minimum-cost assignment of rows to columns (Kuhn-Munkres with potentials, O(n²m)).
+/// Returns the column of every row. If there are more rows than columns, some rows stay
+/// unassigned instead.
+pub fn solve(costs: &[Vec<f64>]) -> Vec<Option<usize>> {
+    let rows = costs.len();
+    let columns = costs.first().map_or(0, Vec::len);
+    if rows == 0 || columns == 0 {
+        return vec![None; rows];
+    }
+    if rows > columns {
+        let transposed: Vec<Vec<f64>> = (0..columns)
+            .map(|j| costs.iter().map(|row| row[j]).collect())
+            .collect();
+        let mut assignment = vec![None; rows];
+        for (column, row) in solve(&transposed).into_iter().enumerate() {
+            if let Some(row) = row {
+                assignment[row] = Some(column);
+            }
+        }
+        return assignment;
+    }
+
+    // Rows and columns are counted from 1, column 0 stands for "not matched yet"
+    let mut row_potential = vec![0.0; rows + 1];
+    let mut column_potential = vec![0.0; columns + 1];
+    let mut matched_row = vec![0usize; columns + 1];
+    let mut previous = vec![0usize; columns + 1];
+
+    for row in 1..=rows {
+        matched_row[0] = row;
+        let mut column = 0;
+        let mut slack = vec![f64::INFINITY; columns + 1];
+        let mut visited = vec![false; columns + 1];
+
+        // Grow an alternating path until it reaches a free column
+        loop {
+            visited[column] = true;
+            let current_row = matched_row[column];
+            let mut delta = f64::INFINITY;
+            let mut next = 0;
+            for j in 1..=columns {
+                if visited[j] {
+                    continue;
+                }
+                let reduced = costs[current_row - 1][j - 1]
+                    - row_potential[current_row]
+                    - column_potential[j];
+                if reduced < slack[j] {
+                    slack[j] = reduced;
+                    previous[j] = column;
+                }
+                if slack[j] < delta {
+                    delta = slack[j];
+                    next = j;
+                }
+            }
+            for j in 0..=columns {
+                if visited[j] {
+                    row_potential[matched_row[j]] += delta;
+                    column_potential[j] -= delta;
+                } else {
+                    slack[j] -= delta;
+                }
+            }
+            column = next;
+            if matched_row[column] == 0 {
+                break;
+            }
+        }
+
+        // Flip the path
+        while column != 0 {
+            let before = previous[column];
+            matched_row[column] = matched_row[before];
+            column = before;
+        }
+    }
+
+    let mut assignment = vec![None; rows];
+    for (column, row) in matched_row.iter().enumerate().skip(1) {
+        if *row != 0 {
+            assignment[row - 1] = Some(column - 1);
+        }
+    }
+    assignment
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_solve_finds_the_cheapest_assignment() {
+    // Greedy would give row 0 column 0 and end up paying 1 + 10
+    let costs = vec![vec![1.0, 2.0], vec![1.5, 10.0]];
+    assert_eq!(solve(&costs), [Some(1), Some(0)]);
+
+    let costs = vec![vec![4.0], vec![1.0], vec![3.0]];
+    assert_eq!(solve(&costs), [None, Some(0), None]);
+    assert!(solve(&[]).is_empty());
+}
@@ -1,6 +1,8 @@
 mod cost_functions;
+pub mod hungarian;
 pub mod insert;
 pub mod metric;
+pub mod pooling;
 pub mod remove;
 mod time_functions;
 
@@ -9,12 +11,19 @@ use crate::matching::metric::Metric;
 use crate::matching::time_functions::StraightLineTime;
 use crate::models::{Customer, Scenario, Vehicle};
 use rand::{thread_rng, Rng};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub(crate) const COST_FUNCTION: fn(f64, f64, f64, f64) -> f64 = StraightLineDistance::calculate;
+pub(crate) const TIME_FUNCTION: fn(f64, f64, f64, f64) -> f64 = StraightLineTime::calculate;
+
+/// Pickup distance of pairs the vehicle can't serve, far beyond any real one
+const UNFIT_COST: f64 = 1e12;
+
+type InsertHeuristic = fn(&[Vehicle], &mut Solution, Vec<Customer>);
+type RemoveHeuristic = fn(&Solution, fn(f64, f64, f64, f64) -> f64, usize) -> Vec<(usize, usize)>;
 
-const COST_FUNCTION: fn(f64, f64, f64, f64) -> f64 = StraightLineDistance::calculate;
-const TIME_FUNCTION: fn(f64, f64, f64, f64) -> f64 = StraightLineTime::calculate;
 #[derive(Clone)]
-struct Solution {
+pub(crate) struct Solution {
     route: Vec<Vec<Customer>>,
 }
 
@@ -25,12 +34,21 @@ pub fn compute_assignment(scenario: &Scenario) -> HashMap<String, VecDeque<Strin
         .filter(|x| x.is_available)
         .map(|x| x.to_owned())
         .collect();
+    // Customers a vehicle is already on its way to are not planned again
+    let taken: HashSet<&String> = scenario
+        .vehicles
+        .iter()
+        .filter_map(|x| x.customer_id.as_ref())
+        .collect();
     let c: Vec<Customer> = scenario
         .customers
         .iter()
-        .filter(|x| x.awaiting_service)
+        .filter(|x| x.awaiting_service && !taken.contains(&x.id))
         .map(|x| x.to_owned())
         .collect();
+    if v.is_empty() {
+        return HashMap::new();
+    }
     let initial = construct_initial_solution(&v, &c);
     let optimal = optimize_alns(&v, initial.clone(), 0.95, 50);
     let mut map: HashMap<String, VecDeque<String>> = HashMap::new();
@@ -41,23 +59,85 @@ pub fn compute_assignment(scenario: &Scenario) -> HashMap<String, VecDeque<Strin
     map
 }
 
+/// Where the customer gets off, customers without a destination get off where they got on
+pub(crate) fn destination(customer: &Customer) -> (f64, f64) {
+    (
+        customer.destination_x.unwrap_or(customer.coord_x),
+        customer.destination_y.unwrap_or(customer.coord_y),
+    )
+}
+
+/// The vehicle can drive to the customer and drop them off before its driver has to stop
+/// and before its battery runs out
+pub fn can_serve(vehicle: &Vehicle, customer: &Customer) -> bool {
+    let (x, y) = destination(customer);
+    let trip = |metric: fn(f64, f64, f64, f64) -> f64| {
+        metric(
+            vehicle.coord_x,
+            vehicle.coord_y,
+            customer.coord_x,
+            customer.coord_y,
+        ) + metric(customer.coord_x, customer.coord_y, x, y)
+    };
+
+    vehicle
+        .shift_remaining
+        .is_none_or(|remaining| trip(TIME_FUNCTION) <= remaining)
+        && vehicle
+            .range
+            .is_none_or(|range| trip(COST_FUNCTION) <= range)
+}
+
+/// Pairs vehicles with customers so that the total distance to the pickups, weighted by the
+/// customers' priorities, is minimal
+pub fn compute_batch_assignment(
+    vehicles: &[&Vehicle],
+    customers: &[&Customer],
+) -> Vec<(String, String)> {
+    let costs: Vec<Vec<f64>> = vehicles
+        .iter()
+        .map(|v| {
+            customers
+                .iter()
+                .map(|c| {
+                    if can_serve(v, c) {
+                        // Urgent customers are worth a longer approach
+                        COST_FUNCTION(v.coord_x, v.coord_y, c.coord_x, c.coord_y)
+                            / c.priority.weight()
+                    } else {
+                        // Never chosen over a pair that fits, and dropped below if chosen at all
+                        UNFIT_COST
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    hungarian::solve(&costs)
+        .into_iter()
+        .zip(vehicles)
+        .filter_map(|(customer, vehicle)| {
+            let customer = customers[customer?];
+            can_serve(vehicle, customer).then(|| (vehicle.id.clone(), customer.id.clone()))
+        })
+        .collect()
+}
+
 fn optimize_alns(
-    vehicles: &Vec<Vehicle>,
+    vehicles: &[Vehicle],
     initial: Solution,
     cooling_factor: f64,
     max_iterations: i32,
 ) -> Solution {
     const REMOVAL_FACTOR: f64 = 0.2;
-    let insert_heuristics: Vec<fn(&Vec<Vehicle>, &mut Solution, Vec<Customer>)> = vec![
+    let insert_heuristics: Vec<InsertHeuristic> = vec![
         insert::greedy,
         insert::greedy,
         insert::greedy,
         insert::greedy,
     ];
     let insert_weights = vec![1.0, 1.0, 1.0, 1.0];
-    let remove_heuristics: Vec<
-        fn(&Solution, fn(f64, f64, f64, f64) -> f64, q: usize) -> Vec<(usize, usize)>,
-    > = vec![
+    let remove_heuristics: Vec<RemoveHeuristic> = vec![
         remove::shawn_heuristic,
         remove::shawn_heuristic,
         remove::shawn_heuristic,
@@ -66,32 +146,69 @@ fn optimize_alns(
     let requests: usize = initial.route.iter().map(|x| x.len()).sum();
     let q = (requests as f64 * REMOVAL_FACTOR).floor() as usize;
     let mut current = initial.clone();
-    for i in 0..max_iterations {
+    let mut current_cost = solution_cost(vehicles, &current);
+    let mut best = initial;
+    let mut best_cost = current_cost;
+    let mut temperature = current_cost * 0.05;
+    for _ in 0..max_iterations {
+        let mut candidate = current.clone();
         let removal = select_heuristic(&remove_weights);
-        let indexes: Vec<(usize, usize)> = remove_heuristics[removal](&current, COST_FUNCTION, q);
-        let mut removed: Vec<Customer> = vec![];
-        let mut offsets = vec![];
-        for idx in 0..indexes.len() {
-            offsets.push(0);
+        let mut indexes: Vec<(usize, usize)> =
+            remove_heuristics[removal](&candidate, COST_FUNCTION, q);
+        // Remove from the back of each route so the remaining indexes stay valid
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let removed: Vec<Customer> = indexes
+            .into_iter()
+            .map(|(i, j)| candidate.route[i].remove(j))
+            .collect();
+        let insert = select_heuristic(&insert_weights);
+        insert_heuristics[insert](vehicles, &mut candidate, removed);
+
+        // Simulated annealing acceptance
+        let cost = solution_cost(vehicles, &candidate);
+        if cost < current_cost
+            || thread_rng().gen::<f64>() < ((current_cost - cost) / temperature).exp()
+        {
+            current = candidate;
+            current_cost = cost;
+            if cost < best_cost {
+                best = current.clone();
+                best_cost = cost;
+            }
         }
-        for (i, j) in indexes {
-            removed.push(current.route[i][j - offsets[i]].clone());
-            current.route.get_mut(i).unwrap().remove(j - offsets[i]);
-            offsets[i] += 1;
+        temperature *= cooling_factor;
+    }
+    best
+}
+
+/// Total distance driven when every vehicle serves its route in order
+fn solution_cost(vehicles: &[Vehicle], solution: &Solution) -> f64 {
+    let mut cost = 0.0;
+    for (vehicle, route) in vehicles.iter().zip(solution.route.iter()) {
+        let mut x = vehicle.coord_x;
+        let mut y = vehicle.coord_y;
+        let mut driven = 0.0;
+        for customer in route {
+            let (dest_x, dest_y) = destination(customer);
+            let approach = COST_FUNCTION(x, y, customer.coord_x, customer.coord_y);
+            let ride = COST_FUNCTION(customer.coord_x, customer.coord_y, dest_x, dest_y);
+            // Urgent customers add a penalty for every metre driven before their pickup
+            cost += approach + ride + (customer.priority.weight() - 1.0) * (driven + approach);
+            driven += approach + ride;
+            x = dest_x;
+            y = dest_y;
         }
-        let insert = select_heuristic(&insert_weights);
-        insert_heuristics[insert](&vehicles, &mut current, removed);
     }
-    initial
+    cost
 }
 
-fn select_heuristic(weights: &Vec<f64>) -> usize {
+fn select_heuristic(weights: &[f64]) -> usize {
     let sum = weights.iter().sum();
     let mut random = thread_rng().gen_range(0.0..sum);
     let mut selected: usize = 0;
     loop {
-        random = random - weights.get(selected).unwrap();
-        if random > 0.0 {
+        random -= weights.get(selected).unwrap();
+        if random < 0.0 || selected + 1 == weights.len() {
             break;
         }
         selected += 1;
@@ -99,8 +216,8 @@ fn select_heuristic(weights: &Vec<f64>) -> usize {
     selected
 }
 
-fn construct_initial_solution(vehicles: &Vec<Vehicle>, customers: &Vec<Customer>) -> Solution {
-    let mut remaining = customers.clone();
+fn construct_initial_solution(vehicles: &[Vehicle], customers: &[Customer]) -> Solution {
+    let mut remaining = customers.to_vec();
     let mut current_id = 0;
     let mut s = Solution { route: vec![] };
     for _vehicle in vehicles {
@@ -115,8 +232,7 @@ fn construct_initial_solution(vehicles: &Vec<Vehicle>, customers: &Vec<Customer>
             y_vehicle = vehicle.coord_y;
         } else {
             let last_customer = s.route.get(current_id).unwrap().last().unwrap();
-            x_vehicle = last_customer.destination_x.unwrap();
-            y_vehicle = last_customer.destination_y.unwrap();
+            (x_vehicle, y_vehicle) = destination(last_customer);
         }
         let mut best = f64::MAX;
         let mut best_idx = 0;
@@ -147,24 +263,22 @@ fn test_initial_solution1() {
             coord_x: 0.0,
             coord_y: 0.0,
             is_available: true,
-            vehicle_speed: None,
-            customer_id: None,
             remaining_travel_time: Some(0.0),
             distance_travelled: Some(0.0),
             active_time: Some(0.0),
             number_of_trips: Some(0),
+            ..Default::default()
         },
         Vehicle {
             id: "v2".to_string(),
             coord_x: 0.5,
             coord_y: 0.5,
             is_available: false,
-            vehicle_speed: None,
-            customer_id: None,
             remaining_travel_time: Some(0.0),
             distance_travelled: Some(0.0),
             active_time: Some(0.0),
             number_of_trips: Some(0),
+            ..Default::default()
         },
     ];
     let customers = vec![
@@ -175,6 +289,7 @@ fn test_initial_solution1() {
             destination_x: Some(0.33),
             destination_y: Some(-0.20),
             awaiting_service: false,
+            ..Default::default()
         },
         Customer {
             id: "c2".to_string(),
@@ -183,6 +298,7 @@ fn test_initial_solution1() {
             destination_x: Some(0.90),
             destination_y: Some(0.90),
             awaiting_service: false,
+            ..Default::default()
         },
         Customer {
             id: "c3".to_string(),
@@ -191,6 +307,7 @@ fn test_initial_solution1() {
             destination_x: Some(-0.5),
             destination_y: Some(-0.5),
             awaiting_service: false,
+            ..Default::default()
         },
         Customer {
             id: "c4".to_string(),
@@ -199,6 +316,7 @@ fn test_initial_solution1() {
             destination_x: Some(0.0),
             destination_y: Some(0.0),
             awaiting_service: false,
+            ..Default::default()
         },
     ];
     let s = construct_initial_solution(&vehicles, &customers);
@@ -210,3 +328,113 @@ fn test_initial_solution1() {
     assert_eq!(s.route[1][0].id, "c1");
     assert_eq!(s.route[1][1].id, "c2");
 }
+
+#[test]
+fn test_customers_without_destination_are_planned() {
+    let vehicle = |id: &str, x: f64| Vehicle {
+        id: id.to_string(),
+        coord_x: x,
+        coord_y: 11.5,
+        is_available: true,
+        ..Default::default()
+    };
+    let customers: Vec<Customer> = (0..10)
+        .map(|i| Customer {
+            id: format!("c{}", i),
+            coord_x: 48.1 + i as f64 * 0.001,
+            coord_y: 11.5,
+            destination_x: (i % 2 == 0).then_some(48.2),
+            awaiting_service: true,
+            ..Default::default()
+        })
+        .collect();
+    let vehicles = vec![vehicle("v1", 48.1), vehicle("v2", 48.2)];
+
+    let s = construct_initial_solution(&vehicles, &customers);
+    assert_eq!(s.route.iter().map(|route| route.len()).sum::<usize>(), 10);
+}
+
+#[cfg(test)]
+fn random_scenario(vehicles: usize, customers: usize) -> Scenario {
+    let mut rng = thread_rng();
+    let vehicles = (0..vehicles)
+        .map(|i| Vehicle {
+            id: format!("v{}", i),
+            coord_x: 48.1 + rng.gen_range(0.0..0.05),
+            coord_y: 11.5 + rng.gen_range(0.0..0.05),
+            is_available: true,
+            ..Default::default()
+        })
+        .collect();
+    let customers = (0..customers)
+        .map(|i| Customer {
+            id: format!("c{}", i),
+            coord_x: 48.1 + rng.gen_range(0.0..0.05),
+            coord_y: 11.5 + rng.gen_range(0.0..0.05),
+            // Every fifth customer has no destination
+            destination_x: (i % 5 != 0).then(|| 48.1 + rng.gen_range(0.0..0.05)),
+            destination_y: (i % 5 != 0).then(|| 11.5 + rng.gen_range(0.0..0.05)),
+            awaiting_service: true,
+            ..Default::default()
+        })
+        .collect();
+    Scenario {
+        id: "s".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles,
+        customers,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_compute_assignment_plans_every_customer_once() {
+    let scenario = random_scenario(5, 20);
+
+    let assignment = compute_assignment(&scenario);
+    let mut planned: Vec<&String> = assignment.values().flatten().collect();
+    planned.sort();
+    planned.dedup();
+    assert_eq!(assignment.len(), 5);
+    assert_eq!(planned.len(), 20);
+}
+
+#[test]
+fn test_alns_never_returns_a_worse_solution() {
+    for _ in 0..20 {
+        let scenario = random_scenario(4, 15);
+        let initial = construct_initial_solution(&scenario.vehicles, &scenario.customers);
+        let initial_cost = solution_cost(&scenario.vehicles, &initial);
+
+        let optimized = optimize_alns(&scenario.vehicles, initial, 0.95, 50);
+        assert!(solution_cost(&scenario.vehicles, &optimized) <= initial_cost);
+        assert_eq!(optimized.route.iter().map(Vec::len).sum::<usize>(), 15);
+    }
+}
+
+#[test]
+fn test_greedy_fills_empty_routes() {
+    let scenario = random_scenario(3, 4);
+    let mut solution = Solution {
+        route: vec![vec![]; 3],
+    };
+
+    insert::greedy(
+        &scenario.vehicles,
+        &mut solution,
+        scenario.customers.clone(),
+    );
+    assert_eq!(solution.route.iter().map(Vec::len).sum::<usize>(), 4);
+}
+
+#[test]
+fn test_select_heuristic_stays_in_bounds() {
+    let weights = [1.0, 2.0, 0.5];
+    let mut selected = [0; 3];
+    for _ in 0..10_000 {
+        selected[select_heuristic(&weights)] += 1;
+    }
+    // Every heuristic is picked, roughly by its weight
+    assert!(selected.iter().all(|&count| count > 0));
+    assert!(selected[1] > selected[0] && selected[0] > selected[2]);
+}
@@ -0,0 +1,278 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::matching::{can_serve, destination, COST_FUNCTION};
+use crate::models::{Customer, Scenario, Vehicle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StopKind {
+    Pickup,
+    Dropoff,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Stop {
+    pub customer_id: String,
+    pub kind: StopKind,
+    pub coord_x: f64,
+    pub coord_y: f64,
+}
+
+/// Limits for sharing rides
+#[derive(Debug, Clone, Copy)]
+pub struct PoolingConfig {
+    /// For vehicles that don't state their capacity
+    pub default_capacity: u32,
+    /// How much longer than the direct trip a shared ride may be, 1.5 allows 50% detour
+    pub max_detour: f64,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        PoolingConfig {
+            default_capacity: 4,
+            max_detour: 1.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PooledRoute {
+    pub vehicle_id: String,
+    pub stops: Vec<Stop>,
+    /// Metres from the vehicle's position through all stops
+    pub distance: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolingPlan {
+    pub routes: Vec<PooledRoute>,
+    /// Customers no vehicle could take within the limits
+    pub unassigned: Vec<String>,
+}
+
+fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    COST_FUNCTION(from.0, from.1, to.0, to.1)
+}
+
+fn position(stop: &Stop) -> (f64, f64) {
+    (stop.coord_x, stop.coord_y)
+}
+
+fn route_distance(start: (f64, f64), stops: &[Stop]) -> f64 {
+    let mut at = start;
+    let mut total = 0.0;
+    for stop in stops {
+        total += distance(at, position(stop));
+        at = position(stop);
+    }
+    total
+}
+
+/// The route respects the capacity and no customer rides much longer than directly
+fn is_feasible(
+    stops: &[Stop],
+    capacity: u32,
+    max_detour: f64,
+    direct: &HashMap<&str, f64>,
+) -> bool {
+    let mut load = 0;
+    let mut boarded: HashMap<&str, f64> = HashMap::new();
+    let mut travelled = 0.0;
+    let mut at: Option<(f64, f64)> = None;
+
+    for stop in stops {
+        if let Some(previous) = at {
+            travelled += distance(previous, position(stop));
+        }
+        at = Some(position(stop));
+
+        match stop.kind {
+            StopKind::Pickup => {
+                load += 1;
+                if load > capacity {
+                    return false;
+                }
+                boarded.insert(&stop.customer_id, travelled);
+            }
+            StopKind::Dropoff => {
+                load -= 1;
+                let Some(boarded_at) = boarded.get(stop.customer_id.as_str()) else {
+                    return false;
+                };
+                let ride = travelled - boarded_at;
+                let direct = direct
+                    .get(stop.customer_id.as_str())
+                    .copied()
+                    .unwrap_or_default();
+                // A little slack so riding alone along the direct line is always allowed
+                if ride > direct * max_detour + 1e-6 {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Plans shared rides as a pickup-and-delivery problem with capacities, inserting every
+/// waiting customer where it adds the least distance without breaking the detour limit.
+/// Urgent customers are inserted first.
+pub fn plan_pooled_routes(scenario: &Scenario, config: PoolingConfig) -> PoolingPlan {
+    let vehicles: Vec<&Vehicle> = scenario
+        .vehicles
+        .iter()
+        .filter(|v| v.customer_id.is_none())
+        .collect();
+    let mut customers: Vec<&Customer> = scenario
+        .customers
+        .iter()
+        .filter(|c| c.awaiting_service)
+        .filter(|c| {
+            !scenario
+                .vehicles
+                .iter()
+                .any(|v| v.customer_id.as_ref() == Some(&c.id))
+        })
+        .collect();
+    customers.sort_by_key(|c| Reverse(c.priority));
+    let direct: HashMap<&str, f64> = customers
+        .iter()
+        .map(|c| {
+            (
+                c.id.as_str(),
+                distance((c.coord_x, c.coord_y), destination(c)),
+            )
+        })
+        .collect();
+
+    let mut routes: Vec<Vec<Stop>> = vec![vec![]; vehicles.len()];
+    let mut unassigned = Vec::new();
+
+    for customer in customers {
+        let pickup = Stop {
+            customer_id: customer.id.clone(),
+            kind: StopKind::Pickup,
+            coord_x: customer.coord_x,
+            coord_y: customer.coord_y,
+        };
+        let (x, y) = destination(customer);
+        let dropoff = Stop {
+            customer_id: customer.id.clone(),
+            kind: StopKind::Dropoff,
+            coord_x: x,
+            coord_y: y,
+        };
+
+        let mut best: Option<(f64, usize, Vec<Stop>)> = None;
+        for (index, vehicle) in vehicles.iter().enumerate() {
+            // The vehicle must at least manage the customer's own trip before its shift or
+            // battery ends
+            if !can_serve(vehicle, customer) {
+                continue;
+            }
+            let start = (vehicle.coord_x, vehicle.coord_y);
+            let capacity = vehicle.capacity.unwrap_or(config.default_capacity);
+            let route = &routes[index];
+            let before = route_distance(start, route);
+
+            for i in 0..=route.len() {
+                for j in i..=route.len() {
+                    let mut candidate = route.clone();
+                    candidate.insert(j, dropoff.clone());
+                    candidate.insert(i, pickup.clone());
+                    if !is_feasible(&candidate, capacity, config.max_detour, &direct) {
+                        continue;
+                    }
+
+                    let added = route_distance(start, &candidate) - before;
+                    if best.as_ref().is_none_or(|(cost, _, _)| added < *cost) {
+                        best = Some((added, index, candidate));
+                    }
+                }
+            }
+        }
+
+        match best {
+            Some((_, index, route)) => routes[index] = route,
+            None => unassigned.push(customer.id.clone()),
+        }
+    }
+
+    PoolingPlan {
+        routes: vehicles
+            .iter()
+            .zip(routes)
+            .filter(|(_, stops)| !stops.is_empty())
+            .map(|(vehicle, stops)| PooledRoute {
+                vehicle_id: vehicle.id.clone(),
+                distance: route_distance((vehicle.coord_x, vehicle.coord_y), &stops),
+                stops,
+            })
+            .collect(),
+        unassigned,
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_customers_going_the_same_way_share_a_vehicle() {
+    let customer = |id: &str, from: f64, to: f64| Customer {
+        id: id.to_string(),
+        coord_x: 48.0 + from,
+        coord_y: 11.5,
+        destination_x: Some(48.0 + to),
+        destination_y: Some(11.5),
+        awaiting_service: true,
+        ..Default::default()
+    };
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 48.0,
+            coord_y: 11.5,
+            is_available: true,
+            capacity: Some(2),
+            ..Default::default()
+        }],
+        customers: vec![
+            customer("c1", 0.01, 0.05),
+            customer("c2", 0.02, 0.04),
+            customer("c3", 0.03, 0.045),
+            // The opposite direction would be far too much of a detour
+            customer("c4", 0.06, 0.0),
+        ],
+        ..Default::default()
+    };
+
+    let plan = plan_pooled_routes(&scenario, PoolingConfig::default());
+    let route = &plan.routes[0];
+    let order: Vec<(&str, StopKind)> = route
+        .stops
+        .iter()
+        .map(|s| (s.customer_id.as_str(), s.kind))
+        .collect();
+    assert_eq!(
+        order,
+        [
+            ("c1", StopKind::Pickup),
+            ("c2", StopKind::Pickup),
+            ("c2", StopKind::Dropoff),
+            ("c3", StopKind::Pickup),
+            ("c3", StopKind::Dropoff),
+            ("c1", StopKind::Dropoff),
+            ("c4", StopKind::Pickup),
+            ("c4", StopKind::Dropoff),
+        ]
+    );
+    assert!(plan.unassigned.is_empty());
+}
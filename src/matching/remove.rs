@@ -1,4 +1,4 @@
-use crate::matching::Solution;
+use crate::matching::{destination, Solution};
 use rand::{thread_rng, Rng};
 
 pub(crate) fn shawn_heuristic(
@@ -10,27 +10,26 @@ pub(crate) fn shawn_heuristic(
     for i in 0..solution.route.len() {
         for j in 0..solution.route[i].len() {
             let customer = &solution.route[i][j];
+            let (dest_x, dest_y) = destination(customer);
             flattened.push((
-                cost(
-                    customer.coord_x,
-                    customer.coord_y,
-                    customer.destination_x.unwrap(),
-                    customer.destination_y.unwrap(),
-                ),
+                cost(customer.coord_x, customer.coord_y, dest_x, dest_y),
                 (i, j),
             ));
         }
     }
+    if flattened.is_empty() {
+        return vec![];
+    }
     let r = thread_rng().gen_range(0..flattened.len());
     let rcost = flattened[r].0;
-    flattened.sort_by(|a, b| {
-        ((rcost - a.0).abs() * thread_rng().gen_range(0.95..1.05))
-            .partial_cmp(&(rcost - &b.0).abs())
-            .unwrap()
-    });
+    // The noise is drawn once per request, sorting needs a consistent order
+    for entry in flattened.iter_mut() {
+        entry.0 = (rcost - entry.0).abs() * thread_rng().gen_range(0.95..1.05);
+    }
+    flattened.sort_by(|a, b| a.0.total_cmp(&b.0));
     flattened
         .iter()
-        .take(q as usize)
-        .map(|(i, j)| j.to_owned())
+        .take(q)
+        .map(|(_, j)| j.to_owned())
         .collect()
 }
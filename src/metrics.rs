@@ -0,0 +1,160 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+/// Everything exported on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Calls to the runner and the backend, including retries, by service, endpoint and outcome
+    pub http_request_duration: HistogramVec,
+    /// Retried calls to the runner and the backend, by service and endpoint
+    pub http_retries: IntCounterVec,
+    /// One round of dispatching, updating the runner and fetching the scenario, by dispatcher
+    pub tick_duration: HistogramVec,
+    /// Time spent deciding on assignments, by dispatcher
+    pub dispatch_duration: HistogramVec,
+    /// Time ALNS needs to plan all routes
+    pub alns_solve_duration: Histogram,
+    /// Vehicle assignments the runner rejected, by dispatcher
+    pub failed_vehicle_updates: IntCounterVec,
+    /// Open websocket connections
+    pub websocket_connections: IntGauge,
+    /// Simulations and replays that haven't ended yet
+    pub active_runs: IntGauge,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+    let seconds =
+        |name: &str, help: &str, buckets: Vec<f64>| HistogramOpts::new(name, help).buckets(buckets);
+    // 1ms up to about 30s
+    let latency_buckets = || exponential_buckets(0.001, 2.0, 16).unwrap();
+
+    let metrics = Metrics {
+        http_request_duration: HistogramVec::new(
+            seconds(
+                "upstream_request_duration_seconds",
+                "Duration of calls to the runner and the backend, including retries",
+                latency_buckets(),
+            ),
+            &["service", "endpoint", "outcome"],
+        )
+        .unwrap(),
+        http_retries: IntCounterVec::new(
+            Opts::new(
+                "upstream_retries_total",
+                "Calls to the runner and the backend that were sent again",
+            ),
+            &["service", "endpoint"],
+        )
+        .unwrap(),
+        tick_duration: HistogramVec::new(
+            seconds(
+                "simulation_tick_duration_seconds",
+                "Duration of one simulation tick",
+                latency_buckets(),
+            ),
+            &["dispatcher"],
+        )
+        .unwrap(),
+        dispatch_duration: HistogramVec::new(
+            seconds(
+                "dispatch_duration_seconds",
+                "Time the dispatcher spent deciding on assignments",
+                exponential_buckets(0.0001, 4.0, 12).unwrap(),
+            ),
+            &["dispatcher"],
+        )
+        .unwrap(),
+        alns_solve_duration: Histogram::with_opts(seconds(
+            "alns_solve_duration_seconds",
+            "Time ALNS needed to plan all routes",
+            latency_buckets(),
+        ))
+        .unwrap(),
+        failed_vehicle_updates: IntCounterVec::new(
+            Opts::new(
+                "failed_vehicle_updates_total",
+                "Vehicle assignments the runner rejected",
+            ),
+            &["dispatcher"],
+        )
+        .unwrap(),
+        websocket_connections: IntGauge::new("websocket_connections", "Open websocket connections")
+            .unwrap(),
+        active_runs: IntGauge::new("active_runs", "Simulations and replays that are running")
+            .unwrap(),
+        registry,
+    };
+
+    let registry = &metrics.registry;
+    registry
+        .register(Box::new(metrics.http_request_duration.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.http_retries.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.tick_duration.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.dispatch_duration.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.alns_solve_duration.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.failed_vehicle_updates.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.websocket_connections.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.active_runs.clone()))
+        .unwrap();
+    metrics
+});
+
+/// All metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .expect("Failed to encode metrics");
+    String::from_utf8(buffer).expect("Metrics are not UTF-8")
+}
+
+/// Keeps a gauge raised while it is alive
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_metrics_are_rendered() {
+    METRICS
+        .failed_vehicle_updates
+        .with_label_values(&["nearest"])
+        .inc();
+    METRICS.alns_solve_duration.observe(0.5);
+
+    let text = render();
+    assert!(text.contains("failed_vehicle_updates_total{dispatcher=\"nearest\"}"));
+    assert!(text.contains("alns_solve_duration_seconds_bucket{le=\"0.512\"}"));
+    assert!(text.contains("# TYPE active_runs gauge"));
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use warp::filters::ws::Message;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Scenario {
     pub id: String,
@@ -10,9 +11,12 @@ pub struct Scenario {
     pub status: String,
     pub vehicles: Vec<Vehicle>,
     pub customers: Vec<Customer>,
+    /// Where electric vehicles charge, the runner doesn't know about them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chargers: Vec<Charger>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Customer {
     pub id: String,
@@ -21,9 +25,36 @@ pub struct Customer {
     pub destination_x: Option<f64>,
     pub destination_y: Option<f64>,
     pub awaiting_service: bool,
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Service class of a customer, later classes are more urgent
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Standard,
+    Premium,
+    Accessibility,
+    Medical,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl Priority {
+    /// How much the distance driven before the pickup counts compared to a standard customer
+    pub fn weight(self) -> f64 {
+        match self {
+            Priority::Standard => 1.0,
+            Priority::Premium => 2.0,
+            Priority::Accessibility => 3.0,
+            Priority::Medical => 5.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Vehicle {
     pub id: String,
@@ -36,6 +67,30 @@ pub struct Vehicle {
     pub distance_travelled: Option<f64>,
     pub active_time: Option<f64>,
     pub number_of_trips: Option<i64>,
+    /// Passengers that fit at once, the runner doesn't report it and carries one at a time
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    /// Seconds until the driver's shift ends or their next break starts, from the shift list
+    #[serde(default)]
+    pub shift_remaining: Option<f64>,
+    /// kWh the battery holds, only set for electric vehicles
+    #[serde(default)]
+    pub battery_capacity: Option<f64>,
+    /// kWh left, estimated from the distance driven since the runner doesn't report it
+    #[serde(default)]
+    pub state_of_charge: Option<f64>,
+    /// Metres the vehicle can still drive before it has to charge
+    #[serde(default)]
+    pub range: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Charger {
+    pub id: String,
+    pub coord_x: f64,
+    pub coord_y: f64,
+    pub power_kw: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,7 +99,7 @@ pub struct UpdateScenario {
     pub vehicles: Vec<UpdateVehicle>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateVehicle {
     pub id: String,
@@ -0,0 +1,82 @@
+use utoipa::OpenApi;
+
+use crate::control::{Command, CommandMessage};
+use crate::delta::{CustomerDelta, ScenarioDelta, VehicleDelta};
+use crate::dispatch::Algorithm;
+use crate::events::{Envelope, Event};
+use crate::health::{Readiness, ServiceStatus, VersionInfo};
+use crate::kpi::Kpis;
+use crate::models::{Customer, Scenario, Vehicle};
+use crate::session::{RunInfo, RunSettings, RunState};
+use crate::{ErrorMsg, RunDetails, RunSummary};
+
+/// The HTTP API, served at `/openapi.json`. Websocket messages are described by the
+/// `CommandMessage` (client to server) and `Envelope` (server to client) schemas.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Fleet simulation API"),
+    paths(
+        crate::handle_ws_route,
+        crate::list_runs,
+        crate::create_run,
+        crate::get_run,
+        crate::get_run_summary,
+        crate::control_run,
+        crate::create_scenario,
+        crate::export_scenario,
+        crate::import_scenario,
+        crate::import_csv_scenario,
+        crate::healthz,
+        crate::readyz,
+        crate::version,
+        crate::metrics,
+    ),
+    components(schemas(
+        Scenario,
+        Vehicle,
+        Customer,
+        Algorithm,
+        Kpis,
+        RunInfo,
+        RunSettings,
+        RunState,
+        RunDetails,
+        RunSummary,
+        ErrorMsg,
+        CommandMessage,
+        Command,
+        Envelope,
+        Event,
+        ScenarioDelta,
+        VehicleDelta,
+        CustomerDelta,
+        Readiness,
+        ServiceStatus,
+        VersionInfo,
+    )),
+    tags(
+        (name = "runs", description = "Running, watching and inspecting simulations"),
+        (name = "scenarios", description = "Creating, importing and exporting scenarios"),
+        (name = "health", description = "Probes for docker-compose and the reverse proxy"),
+    )
+)]
+pub struct ApiDoc;
+
+/*=================TESTS===============================*/
+
+/// Regenerate the checked-in document with `UPDATE_OPENAPI=1 cargo test`
+#[test]
+fn test_openapi_document_is_up_to_date() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+    let generated = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+
+    if std::env::var_os("UPDATE_OPENAPI").is_some() {
+        std::fs::write(path, &generated).unwrap();
+    }
+
+    let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+    assert!(
+        checked_in == generated,
+        "openapi.json is outdated, run `UPDATE_OPENAPI=1 cargo test` and commit it"
+    );
+}
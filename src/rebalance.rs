@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::models::{Scenario, Vehicle};
+
+/// Cells per side of the demand grid
+const GRID_SIZE: usize = 8;
+
+/// A proposal to send an idle vehicle towards where customers are expected
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Reposition {
+    pub vehicle_id: String,
+    pub coord_x: f64,
+    pub coord_y: f64,
+}
+
+/// Where customers showed up so far, counted per cell of a grid over the scenario's area
+#[derive(Debug, Clone)]
+pub struct DemandGrid {
+    min: (f64, f64),
+    cell: (f64, f64),
+    counts: Vec<f64>,
+}
+
+impl DemandGrid {
+    /// A grid spanning every vehicle and customer of `scenario`
+    pub fn covering(scenario: &Scenario) -> Self {
+        let points = scenario
+            .vehicles
+            .iter()
+            .map(|v| (v.coord_x, v.coord_y))
+            .chain(scenario.customers.iter().map(|c| (c.coord_x, c.coord_y)));
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for (x, y) in points {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        if min.0 > max.0 {
+            (min, max) = ((0.0, 0.0), (1.0, 1.0));
+        }
+
+        // Degenerate areas still get cells with a size
+        let size = |from: f64, to: f64| ((to - from) / GRID_SIZE as f64).max(f64::EPSILON);
+        DemandGrid {
+            min,
+            cell: (size(min.0, max.0), size(min.1, max.1)),
+            counts: vec![0.0; GRID_SIZE * GRID_SIZE],
+        }
+    }
+
+    /// The cell containing a point, points outside the grid belong to the closest edge cell
+    pub fn cell_of(&self, x: f64, y: f64) -> usize {
+        let index = |value: f64, min: f64, size: f64| {
+            (((value - min) / size).max(0.0) as usize).min(GRID_SIZE - 1)
+        };
+        index(y, self.min.1, self.cell.1) * GRID_SIZE + index(x, self.min.0, self.cell.0)
+    }
+
+    fn center_of(&self, cell: usize) -> (f64, f64) {
+        let (row, col) = (cell / GRID_SIZE, cell % GRID_SIZE);
+        (
+            self.min.0 + (col as f64 + 0.5) * self.cell.0,
+            self.min.1 + (row as f64 + 0.5) * self.cell.1,
+        )
+    }
+
+    pub fn record(&mut self, x: f64, y: f64) {
+        let cell = self.cell_of(x, y);
+        self.counts[cell] += 1.0;
+    }
+
+    /// Moves idle vehicles towards the busiest cells that no vehicle waits in yet.
+    /// Nothing is proposed while customers still wait, the dispatcher takes care of those.
+    pub fn propose(&self, scenario: &Scenario) -> Vec<Reposition> {
+        let assigned: HashSet<&str> = scenario
+            .vehicles
+            .iter()
+            .filter_map(|v| v.customer_id.as_deref())
+            .collect();
+        if scenario
+            .customers
+            .iter()
+            .any(|c| c.awaiting_service && !assigned.contains(c.id.as_str()))
+        {
+            return vec![];
+        }
+
+        let mut idle: Vec<_> = scenario
+            .vehicles
+            .iter()
+            .filter(|v| v.customer_id.is_none())
+            .collect();
+        let mut covered: HashSet<usize> = idle
+            .iter()
+            .map(|v| self.cell_of(v.coord_x, v.coord_y))
+            .collect();
+
+        let mut hotspots: Vec<usize> = (0..self.counts.len())
+            .filter(|cell| self.counts[*cell] > 0.0)
+            .collect();
+        hotspots.sort_by(|a, b| self.counts[*b].total_cmp(&self.counts[*a]));
+
+        let mut moves = Vec::new();
+        for cell in hotspots {
+            if covered.contains(&cell) {
+                continue;
+            }
+            let (x, y) = self.center_of(cell);
+
+            // Take the closest vehicle that isn't needed where it stands
+            let Some(closest) = idle
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| {
+                    let home = self.cell_of(v.coord_x, v.coord_y);
+                    self.counts[home] < self.counts[cell]
+                })
+                .min_by(|(_, a), (_, b)| {
+                    let distance = |v: &Vehicle| (v.coord_x - x).powi(2) + (v.coord_y - y).powi(2);
+                    distance(a).total_cmp(&distance(b))
+                })
+                .map(|(i, _)| i)
+            else {
+                break;
+            };
+
+            let vehicle = idle.swap_remove(closest);
+            covered.insert(cell);
+            moves.push(Reposition {
+                vehicle_id: vehicle.id.clone(),
+                coord_x: x,
+                coord_y: y,
+            });
+        }
+        moves
+    }
+}
+
+/// Learns demand over a run and keeps the current proposals
+pub struct Rebalancer {
+    grid: DemandGrid,
+    seen: HashSet<String>,
+    proposed: Vec<Reposition>,
+}
+
+impl Rebalancer {
+    pub fn new(scenario: &Scenario) -> Self {
+        Rebalancer {
+            grid: DemandGrid::covering(scenario),
+            seen: HashSet::new(),
+            proposed: vec![],
+        }
+    }
+
+    /// Takes in the customers of a tick, returns new proposals if they changed
+    pub fn update(&mut self, scenario: &Scenario) -> Option<Vec<Reposition>> {
+        for customer in &scenario.customers {
+            if self.seen.insert(customer.id.clone()) {
+                self.grid.record(customer.coord_x, customer.coord_y);
+            }
+        }
+
+        let proposed = self.grid.propose(scenario);
+        if proposed == self.proposed {
+            return None;
+        }
+        self.proposed = proposed.clone();
+        Some(proposed)
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_idle_vehicles_move_to_demand() {
+    use crate::models::Customer;
+
+    let vehicle = |id: &str, x: f64, customer: Option<&str>| Vehicle {
+        id: id.to_string(),
+        coord_x: x,
+        coord_y: 0.0,
+        is_available: customer.is_none(),
+        customer_id: customer.map(str::to_string),
+        ..Default::default()
+    };
+    let customer = |id: &str, x: f64, waiting: bool| Customer {
+        id: id.to_string(),
+        coord_x: x,
+        coord_y: 0.0,
+        awaiting_service: waiting,
+        ..Default::default()
+    };
+    let mut scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![vehicle("v1", 0.0, None), vehicle("v2", 1.0, Some("c3"))],
+        customers: vec![
+            customer("c1", 8.0, false),
+            customer("c2", 8.0, false),
+            customer("c3", 8.0, true),
+        ],
+        ..Default::default()
+    };
+
+    let mut rebalancer = Rebalancer::new(&scenario);
+    let moves = rebalancer.update(&scenario).unwrap();
+    assert_eq!(moves.len(), 1);
+    assert_eq!(moves[0].vehicle_id, "v1");
+    assert!(moves[0].coord_x > 7.0);
+    assert!(rebalancer.update(&scenario).is_none());
+
+    // Waiting customers come first
+    scenario.vehicles[1].customer_id = None;
+    assert_eq!(rebalancer.update(&scenario), Some(vec![]));
+}
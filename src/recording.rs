@@ -0,0 +1,266 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::models::{Scenario, UpdateScenario, UpdateScenarioResponse};
+
+/// One line of a recording: the scenario the dispatcher saw, what it sent to the runner
+/// and what the runner answered. The last line of a finished run has no update.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedTick {
+    pub tick: u64,
+    /// Time since the recording was started, used to replay with the original pacing
+    pub elapsed_ms: u64,
+    /// How far into the run the tick was on the runner's clock, missing in older recordings
+    #[serde(default)]
+    pub run_time_ms: Option<u64>,
+    /// The dispatcher that planned the update, missing in older recordings
+    #[serde(default)]
+    pub dispatcher: Option<String>,
+    pub scenario: Scenario,
+    pub update: Option<UpdateScenario>,
+    pub response: Option<UpdateScenarioResponse>,
+}
+
+impl RecordedTick {
+    /// How far into the run the tick was, assuming real time for older recordings
+    pub fn run_time(&self) -> Duration {
+        Duration::from_millis(self.run_time_ms.unwrap_or(self.elapsed_ms))
+    }
+}
+
+/// Returns where the recording for a scenario is stored
+pub fn recording_path(recordings_dir: &Path, scenario_id: &str) -> anyhow::Result<PathBuf> {
+    crate::scenario_io::check_scenario_id(scenario_id)?;
+    Ok(recordings_dir.join(format!("{}.jsonl", scenario_id)))
+}
+
+/// Appends every tick of a run to a JSON Lines file. Lines are written by a thread of their
+/// own, so a slow disk doesn't hold up the run. If writing fails, the error is logged and
+/// the rest of the run isn't recorded.
+pub struct Recorder {
+    lines: Option<mpsc::Sender<String>>,
+    writer: Option<thread::JoinHandle<()>>,
+    started: Instant,
+    tick: u64,
+}
+
+impl Recorder {
+    /// Starts a new recording, replacing an older recording of the same scenario
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = LineWriter::new(File::create(path)?);
+
+        let (lines, received) = mpsc::channel::<String>();
+        let path = path.to_path_buf();
+        let writer = thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                for line in received {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        error!(
+                            "Failed to write recording {}, recording is turned off: {}",
+                            path.display(),
+                            e
+                        );
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(Recorder {
+            lines: Some(lines),
+            writer: Some(writer),
+            started: Instant::now(),
+            tick: 0,
+        })
+    }
+
+    /// Queues a tick for writing, does nothing once recording is turned off
+    pub fn record(
+        &mut self,
+        run_time: Duration,
+        dispatcher: &str,
+        scenario: &Scenario,
+        update: Option<&UpdateScenario>,
+        response: Option<&UpdateScenarioResponse>,
+    ) {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct RecordedTickRef<'a> {
+            tick: u64,
+            elapsed_ms: u64,
+            run_time_ms: u64,
+            dispatcher: &'a str,
+            scenario: &'a Scenario,
+            update: Option<&'a UpdateScenario>,
+            response: Option<&'a UpdateScenarioResponse>,
+        }
+
+        let Some(lines) = &self.lines else {
+            return;
+        };
+        let line = match serde_json::to_string(&RecordedTickRef {
+            tick: self.tick,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            run_time_ms: run_time.as_millis() as u64,
+            dispatcher,
+            scenario,
+            update,
+            response,
+        }) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize tick, recording is turned off: {}", e);
+                self.lines = None;
+                return;
+            }
+        };
+        // The writer only hangs up after it failed and logged why
+        if lines.send(line).is_err() {
+            self.lines = None;
+        }
+
+        self.tick += 1;
+    }
+
+    /// Whether ticks are still being recorded
+    #[cfg(test)]
+    fn is_recording(&self) -> bool {
+        self.lines.is_some()
+    }
+
+    /// Waits until every queued tick is written. Blocks, so async tasks call it through
+    /// `spawn_blocking`.
+    pub fn finish(mut self) {
+        self.lines = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Reads a recording tick by tick
+pub struct Replay {
+    lines: std::iter::Peekable<std::io::Lines<BufReader<File>>>,
+    last_elapsed_ms: u64,
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Replay {
+            lines: BufReader::new(File::open(path)?).lines().peekable(),
+            last_elapsed_ms: 0,
+        })
+    }
+
+    /// The scenario of the upcoming tick, without consuming it
+    pub fn peek_scenario(&mut self) -> anyhow::Result<Scenario> {
+        match self.lines.peek() {
+            Some(Ok(line)) => Ok(serde_json::from_str::<RecordedTick>(line)?.scenario),
+            Some(Err(e)) => Err(anyhow::anyhow!("{}", e)),
+            None => Err(anyhow::anyhow!("The recording is empty")),
+        }
+    }
+
+    /// Returns the next tick and how long after the previous one it was recorded
+    pub fn next_tick(&mut self) -> Option<anyhow::Result<(Duration, RecordedTick)>> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        Some(
+            serde_json::from_str::<RecordedTick>(&line)
+                .map_err(Into::into)
+                .map(|tick| {
+                    let delay = tick.elapsed_ms.saturating_sub(self.last_elapsed_ms);
+                    self.last_elapsed_ms = tick.elapsed_ms;
+                    (Duration::from_millis(delay), tick)
+                }),
+        )
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("recording-test-{}.jsonl", std::process::id()));
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        ..Default::default()
+    };
+    let update = UpdateScenario { vehicles: vec![] };
+    let response = UpdateScenarioResponse {
+        failed_to_update: vec!["v1".to_string()],
+        updated_vehicles: vec![],
+    };
+
+    let mut recorder = Recorder::create(&path).unwrap();
+    recorder.record(
+        Duration::ZERO,
+        "nearest",
+        &scenario,
+        Some(&update),
+        Some(&response),
+    );
+    recorder.record(Duration::from_secs(30), "nearest", &scenario, None, None);
+    assert!(recorder.is_recording());
+    recorder.finish();
+
+    let mut replay = Replay::open(&path).unwrap();
+    let (_, first) = replay.next_tick().unwrap().unwrap();
+    assert_eq!(first.tick, 0);
+    assert_eq!(first.response.unwrap().failed_to_update, vec!["v1"]);
+    let (_, last) = replay.next_tick().unwrap().unwrap();
+    assert_eq!(last.tick, 1);
+    assert!(last.update.is_none());
+    assert_eq!(last.run_time_ms, Some(30_000));
+    assert!(replay.next_tick().is_none());
+
+    fs::remove_file(path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_errors_turn_recording_off() {
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        ..Default::default()
+    };
+
+    // Every write to /dev/full fails with "no space left on device"
+    let mut recorder = Recorder::create(Path::new("/dev/full")).unwrap();
+    for _ in 0..100 {
+        recorder.record(Duration::ZERO, "nearest", &scenario, None, None);
+        if !recorder.is_recording() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!recorder.is_recording());
+    recorder.finish();
+}
+
+#[test]
+fn test_recording_path_rejects_odd_ids() {
+    let path = recording_path(Path::new("recordings"), "3f2a-b_1").unwrap();
+    assert_eq!(path, Path::new("recordings/3f2a-b_1.jsonl"));
+    assert!(recording_path(Path::new("recordings"), "../etc/passwd").is_err());
+    assert!(recording_path(Path::new("recordings"), "a.b").is_err());
+    assert!(recording_path(Path::new("recordings"), "").is_err());
+}
@@ -1,79 +1,88 @@
-use std::error::Error;
+use std::time::Duration;
 
-use reqwest::Client;
 use serde::Deserialize;
 
+use crate::error::ClientError;
+use crate::http::{CallKind, ClientConfig, HttpClient};
 use crate::models::{LaunchScenarioResponse, Scenario, UpdateScenario, UpdateScenarioResponse};
 
 #[derive(Debug, Clone)]
 pub struct RunnerClient {
-    client: Client,
+    client: HttpClient,
     base_url: String,
 }
 
 impl RunnerClient {
-    pub fn new(runner_server_base_url: &str) -> Self {
+    pub fn new(runner_server_base_url: &str, config: ClientConfig) -> Self {
         RunnerClient {
-            client: Client::new(),
+            client: HttpClient::new("runner", config),
             base_url: runner_server_base_url.trim_end_matches('/').to_string(),
         }
     }
 
-    async fn get<T: for<'de> Deserialize<'de>>(&self, endpoint: &str) -> Result<T, Box<dyn Error>> {
+    async fn get<T: for<'de> Deserialize<'de>>(
+        &self,
+        name: &'static str,
+        endpoint: &str,
+    ) -> Result<T, ClientError> {
         let url = format!("{}/{}", self.base_url, endpoint);
-        let response = self
-            .client
-            .get(&url)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<T>()
-            .await?;
-        Ok(response)
+        self.client
+            .call(name, CallKind::Read, |client| client.get(&url))
+            .await
+    }
+
+    /// Checks that the runner answers, see [`HttpClient::probe`]
+    pub async fn probe(&self, timeout: Duration) -> Result<Duration, ClientError> {
+        self.client.probe(&self.base_url, timeout).await
+    }
+
+    /// Too many calls to the runner failed lately
+    pub fn is_degraded(&self) -> bool {
+        self.client.is_degraded()
     }
 
     /// Fetches an already initialized scenario
-    pub async fn get_scenario(&self, scenario_id: &str) -> Result<Scenario, Box<dyn Error>> {
+    pub async fn get_scenario(&self, scenario_id: &str) -> Result<Scenario, ClientError> {
         let scenario: Scenario = self
-            .get(&format!("/Scenarios/get_scenario/{}", scenario_id))
+            .get(
+                "get_scenario",
+                &format!("/Scenarios/get_scenario/{}", scenario_id),
+            )
             .await?;
         Ok(scenario)
     }
 
     /// Initializes a scenario from a database scenario - this must be the first step
-    pub async fn initialize_scenario(
-        &self,
-        db_scenario_id: &str,
-    ) -> Result<Scenario, Box<dyn Error>> {
+    pub async fn initialize_scenario(&self, db_scenario_id: &str) -> Result<Scenario, ClientError> {
         #[derive(Deserialize)]
         struct InitializeScenarioResponse {
+            #[allow(dead_code)]
             message: Option<String>,
             error: Option<String>,
             scenario: Option<Scenario>,
         }
 
+        let url = format!(
+            "{}/Scenarios/initialize_scenario?db_scenario_id={}",
+            self.base_url, db_scenario_id
+        );
         let resp: InitializeScenarioResponse = self
             .client
-            .post(&format!(
-                "{}/Scenarios/initialize_scenario?db_scenario_id={}",
-                self.base_url, db_scenario_id
-            ))
-            .header("Content-Type", "application/json")
-            .body("{}")
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
+            .call("initialize_scenario", CallKind::Write, |client| {
+                client
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .body("{}")
+            })
             .await?;
 
         if let Some(scenario) = resp.scenario {
             return Ok(scenario);
         }
 
-        return Err(resp
-            .error
-            .unwrap_or_else(|| "No error from backend, but also no scenario, lol".to_string())
-            .into());
+        Err(ClientError::Runner(resp.error.unwrap_or_else(|| {
+            "No error from backend, but also no scenario, lol".to_string()
+        })))
     }
 
     /// Assigns vehicles to customers.
@@ -82,22 +91,16 @@ impl RunnerClient {
         &self,
         scenario_id: &str,
         update_vehicles: &UpdateScenario,
-    ) -> Result<UpdateScenarioResponse, Box<dyn Error>> {
-        let scenario: UpdateScenarioResponse = self
-            .client
-            .put(&format!(
-                "{}/Scenarios/update_scenario/{}",
-                self.base_url, scenario_id
-            ))
-            .header("Content-Type", "application/json")
-            .body(serde_json::to_string(update_vehicles)?)
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-
-        Ok(scenario)
+    ) -> Result<UpdateScenarioResponse, ClientError> {
+        let url = format!(
+            "{}/Scenarios/update_scenario/{}",
+            self.base_url, scenario_id
+        );
+        self.client
+            .call("update_scenario", CallKind::Write, |client| {
+                client.put(&url).json(update_vehicles)
+            })
+            .await
     }
 
     /// Launches a scenario with a given speed
@@ -105,22 +108,15 @@ impl RunnerClient {
         &self,
         scenario_id: &str,
         speed: f64,
-    ) -> Result<LaunchScenarioResponse, Box<dyn Error>> {
-        let response = self
-            .client
-            .post(&format!(
-                "{}/Runner/launch_scenario/{}?speed={}",
-                self.base_url, scenario_id, speed
-            ))
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        // Deserialize the response body into the expected struct
-        let start: LaunchScenarioResponse = serde_json::from_str(&response)?;
-
-        Ok(start)
+    ) -> Result<LaunchScenarioResponse, ClientError> {
+        let url = format!(
+            "{}/Runner/launch_scenario/{}?speed={}",
+            self.base_url, scenario_id, speed
+        );
+        self.client
+            .call("launch_scenario", CallKind::Write, |client| {
+                client.post(&url)
+            })
+            .await
     }
 }
@@ -0,0 +1,475 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::{json, Value};
+
+use crate::models::{Charger, Customer, Priority, Scenario, Vehicle};
+
+pub(crate) const VEHICLES_CSV: &str = "vehicles.csv";
+pub(crate) const CUSTOMERS_CSV: &str = "customers.csv";
+/// Optional, most scenarios have no electric vehicles
+pub(crate) const CHARGERS_CSV: &str = "chargers.csv";
+
+/// Scenario ids name files and directories. They are UUIDs, but they come from query
+/// parameters, so anything else is rejected rather than cleaned up and two ids never share
+/// a file.
+pub fn check_scenario_id(scenario_id: &str) -> anyhow::Result<()> {
+    let valid = !scenario_id.is_empty()
+        && scenario_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("'{}' is not a valid scenario id", scenario_id);
+    }
+    Ok(())
+}
+
+/// Returns where an input file of a scenario's runs is kept, e.g. `arrivals.csv`.
+/// Every scenario has a directory of its own in `inputs_dir`.
+pub fn input_path(inputs_dir: &Path, scenario_id: &str, file: &str) -> anyhow::Result<PathBuf> {
+    check_scenario_id(scenario_id)?;
+    Ok(inputs_dir.join(scenario_id).join(file))
+}
+
+/// On-disk representations of a scenario
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScenarioFormat {
+    /// The same JSON shape the runner and backend use
+    Json,
+    /// A directory containing `vehicles.csv`, `customers.csv` and maybe `chargers.csv`
+    Csv,
+    /// A FeatureCollection with vehicle positions, pickups and destinations
+    GeoJson,
+}
+
+impl ScenarioFormat {
+    /// Guesses the format from a path: `.geojson` files are GeoJSON, `.json` files are JSON
+    /// and everything else (usually a directory) is treated as a CSV pair.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("geojson") => ScenarioFormat::GeoJson,
+            Some("json") => ScenarioFormat::Json,
+            _ => ScenarioFormat::Csv,
+        }
+    }
+}
+
+/// Loads a scenario, the format is derived from the path
+pub fn load(path: &Path) -> anyhow::Result<Scenario> {
+    match ScenarioFormat::from_path(path) {
+        ScenarioFormat::Json => {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok(serde_json::from_str(&content)?)
+        }
+        ScenarioFormat::GeoJson => {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            from_geojson(&serde_json::from_str(&content)?)
+        }
+        ScenarioFormat::Csv => {
+            let vehicles = fs::read_to_string(path.join(VEHICLES_CSV))
+                .with_context(|| format!("Failed to read {}", path.join(VEHICLES_CSV).display()))?;
+            let customers = fs::read_to_string(path.join(CUSTOMERS_CSV)).with_context(|| {
+                format!("Failed to read {}", path.join(CUSTOMERS_CSV).display())
+            })?;
+
+            let chargers = match fs::read_to_string(path.join(CHARGERS_CSV)) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to read {}", path.join(CHARGERS_CSV).display())
+                    })
+                }
+            };
+
+            // The CSV pair has no room for scenario metadata, so the directory name is the id
+            let id = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .to_string();
+
+            from_csv_files(id, &vehicles, &customers, chargers.as_deref())
+        }
+    }
+}
+
+/// Builds a scenario from the content of `vehicles.csv`, `customers.csv` and maybe `chargers.csv`
+pub fn from_csv_files(
+    id: String,
+    vehicles: &str,
+    customers: &str,
+    chargers: Option<&str>,
+) -> anyhow::Result<Scenario> {
+    Ok(Scenario {
+        id,
+        start_time: None,
+        end_time: None,
+        status: "CREATED".to_string(),
+        vehicles: vehicles_from_csv(vehicles)?,
+        customers: customers_from_csv(customers)?,
+        chargers: chargers
+            .map(chargers_from_csv)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+/// Saves a scenario, the format is derived from the path
+pub fn save(scenario: &Scenario, path: &Path) -> anyhow::Result<()> {
+    match ScenarioFormat::from_path(path) {
+        ScenarioFormat::Json => fs::write(path, serde_json::to_string_pretty(scenario)?)?,
+        ScenarioFormat::GeoJson => {
+            fs::write(path, serde_json::to_string_pretty(&to_geojson(scenario))?)?
+        }
+        ScenarioFormat::Csv => {
+            fs::create_dir_all(path)?;
+            fs::write(
+                path.join(VEHICLES_CSV),
+                vehicles_to_csv(&scenario.vehicles)?,
+            )?;
+            fs::write(
+                path.join(CUSTOMERS_CSV),
+                customers_to_csv(&scenario.customers)?,
+            )?;
+            if !scenario.chargers.is_empty() {
+                fs::write(path.join(CHARGERS_CSV), to_csv(&scenario.chargers)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn vehicles_to_csv(vehicles: &[Vehicle]) -> anyhow::Result<String> {
+    to_csv(vehicles)
+}
+
+pub fn customers_to_csv(customers: &[Customer]) -> anyhow::Result<String> {
+    to_csv(customers)
+}
+
+pub fn vehicles_from_csv(content: &str) -> anyhow::Result<Vec<Vehicle>> {
+    from_csv(content)
+}
+
+pub fn customers_from_csv(content: &str) -> anyhow::Result<Vec<Customer>> {
+    from_csv(content)
+}
+
+pub fn chargers_from_csv(content: &str) -> anyhow::Result<Vec<Charger>> {
+    let chargers: Vec<Charger> = from_csv(content)?;
+    // Charging takes the missing energy divided by the power
+    if let Some(charger) = chargers
+        .iter()
+        .find(|c| !(c.power_kw.is_finite() && c.power_kw > 0.0))
+    {
+        bail!(
+            "Charger {} needs a positive powerKw, not {}",
+            charger.id,
+            charger.power_kw
+        );
+    }
+    Ok(chargers)
+}
+
+fn to_csv<T: serde::Serialize>(rows: &[T]) -> anyhow::Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+fn from_csv<T: serde::de::DeserializeOwned>(content: &str) -> anyhow::Result<Vec<T>> {
+    csv::Reader::from_reader(content.as_bytes())
+        .deserialize()
+        .map(|row| row.map_err(Into::into))
+        .collect()
+}
+
+/// Converts a scenario into a GeoJSON FeatureCollection.
+/// GeoJSON positions are `[longitude, latitude]`, so `coordY` comes first.
+pub fn to_geojson(scenario: &Scenario) -> Value {
+    let mut features = Vec::new();
+
+    for vehicle in &scenario.vehicles {
+        features.push(point_feature(
+            vehicle.coord_x,
+            vehicle.coord_y,
+            "vehicle",
+            serde_json::to_value(vehicle).unwrap_or_default(),
+        ));
+    }
+
+    for customer in &scenario.customers {
+        features.push(point_feature(
+            customer.coord_x,
+            customer.coord_y,
+            "pickup",
+            json!({
+                "id": customer.id,
+                "awaitingService": customer.awaiting_service,
+                "priority": customer.priority,
+            }),
+        ));
+
+        if let (Some(x), Some(y)) = (customer.destination_x, customer.destination_y) {
+            features.push(point_feature(
+                x,
+                y,
+                "destination",
+                json!({ "id": customer.id }),
+            ));
+        }
+    }
+
+    for charger in &scenario.chargers {
+        features.push(point_feature(
+            charger.coord_x,
+            charger.coord_y,
+            "charger",
+            serde_json::to_value(charger).unwrap_or_default(),
+        ));
+    }
+
+    json!({
+        "type": "FeatureCollection",
+        "scenario": {
+            "id": scenario.id,
+            "startTime": scenario.start_time,
+            "endTime": scenario.end_time,
+            "status": scenario.status,
+        },
+        "features": features,
+    })
+}
+
+fn point_feature(coord_x: f64, coord_y: f64, kind: &str, mut properties: Value) -> Value {
+    properties["kind"] = json!(kind);
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            "coordinates": [coord_y, coord_x],
+        },
+        "properties": properties,
+    })
+}
+
+/// Reads a FeatureCollection written by [`to_geojson`]
+pub fn from_geojson(value: &Value) -> anyhow::Result<Scenario> {
+    if value["type"] != "FeatureCollection" {
+        bail!("Expected a GeoJSON FeatureCollection");
+    }
+
+    let meta = &value["scenario"];
+    let mut scenario = Scenario {
+        id: meta["id"].as_str().unwrap_or_default().to_string(),
+        start_time: meta["startTime"].as_str().map(str::to_string),
+        end_time: meta["endTime"].as_str().map(str::to_string),
+        status: meta["status"].as_str().unwrap_or("CREATED").to_string(),
+        vehicles: vec![],
+        customers: vec![],
+        chargers: vec![],
+    };
+
+    let features = value["features"]
+        .as_array()
+        .ok_or_else(|| anyhow!("FeatureCollection has no features"))?;
+
+    let mut destinations = Vec::new();
+    for feature in features {
+        let properties = &feature["properties"];
+        let (coord_x, coord_y) = point_coordinates(feature)?;
+
+        match properties["kind"].as_str() {
+            Some("vehicle") => {
+                let mut vehicle: Vehicle = serde_json::from_value(properties.clone())?;
+                vehicle.coord_x = coord_x;
+                vehicle.coord_y = coord_y;
+                scenario.vehicles.push(vehicle);
+            }
+            Some("pickup") => scenario.customers.push(Customer {
+                id: feature_id(properties)?,
+                coord_x,
+                coord_y,
+                destination_x: None,
+                destination_y: None,
+                awaiting_service: properties["awaitingService"].as_bool().unwrap_or(true),
+                priority: match &properties["priority"] {
+                    Value::Null => Priority::default(),
+                    priority => serde_json::from_value(priority.clone())
+                        .with_context(|| format!("Invalid priority {}", priority))?,
+                },
+            }),
+            Some("charger") => {
+                let mut charger: Charger = serde_json::from_value(properties.clone())?;
+                charger.coord_x = coord_x;
+                charger.coord_y = coord_y;
+                scenario.chargers.push(charger);
+            }
+            Some("destination") => destinations.push((feature_id(properties)?, coord_x, coord_y)),
+            other => bail!("Unknown feature kind {:?}", other),
+        }
+    }
+
+    for (id, x, y) in destinations {
+        let customer = scenario
+            .customers
+            .iter_mut()
+            .find(|c| c.id == id)
+            .ok_or_else(|| anyhow!("Destination for unknown customer {}", id))?;
+        customer.destination_x = Some(x);
+        customer.destination_y = Some(y);
+    }
+
+    Ok(scenario)
+}
+
+fn point_coordinates(feature: &Value) -> anyhow::Result<(f64, f64)> {
+    let coordinates = &feature["geometry"]["coordinates"];
+    match (coordinates[1].as_f64(), coordinates[0].as_f64()) {
+        (Some(x), Some(y)) => Ok((x, y)),
+        _ => bail!("Feature is not a point: {}", feature),
+    }
+}
+
+fn feature_id(properties: &Value) -> anyhow::Result<String> {
+    properties["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Feature without id: {}", properties))
+}
+
+/*=================TESTS===============================*/
+
+#[cfg(test)]
+pub(crate) fn sample_scenario() -> Scenario {
+    Scenario {
+        id: "s1".to_string(),
+        start_time: Some("2024-11-23T10:00:00".to_string()),
+        status: "RUNNING".to_string(),
+        vehicles: vec![Vehicle {
+            id: "v1".to_string(),
+            coord_x: 48.13,
+            coord_y: 11.57,
+            is_available: false,
+            vehicle_speed: Some(12.5),
+            customer_id: Some("c1".to_string()),
+            remaining_travel_time: Some(30.0),
+            distance_travelled: None,
+            active_time: Some(10.0),
+            number_of_trips: Some(1),
+            ..Default::default()
+        }],
+        customers: vec![
+            Customer {
+                id: "c1".to_string(),
+                coord_x: 48.14,
+                coord_y: 11.56,
+                destination_x: Some(48.15),
+                destination_y: Some(11.58),
+                awaiting_service: true,
+                ..Default::default()
+            },
+            Customer {
+                id: "c2".to_string(),
+                coord_x: 48.10,
+                coord_y: 11.50,
+                destination_x: None,
+                destination_y: None,
+                awaiting_service: false,
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_csv_round_trip() {
+    let scenario = sample_scenario();
+    let vehicles = vehicles_from_csv(&vehicles_to_csv(&scenario.vehicles).unwrap()).unwrap();
+    let customers = customers_from_csv(&customers_to_csv(&scenario.customers).unwrap()).unwrap();
+
+    assert_eq!(vehicles.len(), 1);
+    assert_eq!(vehicles[0].customer_id.as_deref(), Some("c1"));
+    assert_eq!(vehicles[0].distance_travelled, None);
+    assert_eq!(customers.len(), 2);
+    assert_eq!(customers[0].destination_x, Some(48.15));
+    assert_eq!(customers[1].destination_x, None);
+}
+
+#[test]
+fn test_chargers_need_power() {
+    let chargers = chargers_from_csv("id,coordX,coordY,powerKw\nch1,48.1,11.5,50\n").unwrap();
+    assert_eq!(chargers[0].power_kw, 50.0);
+
+    for power in ["0", "-11", "NaN", "inf"] {
+        let csv = format!("id,coordX,coordY,powerKw\nch1,48.1,11.5,{}\n", power);
+        assert!(chargers_from_csv(&csv).is_err(), "{}", power);
+    }
+}
+
+#[test]
+fn test_geojson_round_trip() {
+    let scenario = sample_scenario();
+    let geojson = to_geojson(&scenario);
+    assert_eq!(geojson["features"].as_array().unwrap().len(), 4);
+    assert_eq!(geojson["features"][0]["geometry"]["coordinates"][0], 11.57);
+
+    let loaded = from_geojson(&geojson).unwrap();
+    assert_eq!(loaded.id, "s1");
+    assert_eq!(loaded.status, "RUNNING");
+    assert_eq!(loaded.vehicles[0].coord_x, 48.13);
+    assert_eq!(loaded.customers.len(), 2);
+    assert_eq!(loaded.customers[0].destination_y, Some(11.58));
+    assert!(!loaded.customers[1].awaiting_service);
+}
+
+#[test]
+fn test_geojson_priorities() {
+    let mut scenario = sample_scenario();
+    scenario.customers[0].priority = Priority::Medical;
+    let mut geojson = to_geojson(&scenario);
+    assert_eq!(
+        from_geojson(&geojson).unwrap().customers[0].priority,
+        Priority::Medical
+    );
+
+    let pickup = geojson["features"]
+        .as_array_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|f| f["properties"]["kind"] == "pickup")
+        .unwrap();
+    pickup["properties"]
+        .as_object_mut()
+        .unwrap()
+        .remove("priority");
+    assert_eq!(
+        from_geojson(&geojson).unwrap().customers[0].priority,
+        Priority::Standard
+    );
+
+    let pickup = geojson["features"]
+        .as_array_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|f| f["properties"]["kind"] == "pickup")
+        .unwrap();
+    pickup["properties"]["priority"] = json!("vip");
+    assert!(from_geojson(&geojson).is_err());
+}
+
+#[test]
+fn test_inputs_have_a_directory_per_scenario() {
+    let path = input_path(Path::new("inputs"), "3f2a-b_1", "arrivals.csv").unwrap();
+    assert_eq!(path, Path::new("inputs/3f2a-b_1/arrivals.csv"));
+    assert!(input_path(Path::new("inputs"), "..", "arrivals.csv").is_err());
+}
@@ -0,0 +1,757 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::{sleep, timeout},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, info_span, warn, Instrument, Span};
+use utoipa::ToSchema;
+
+use crate::battery::BatteryModel;
+use crate::control::CommandMessage;
+use crate::dispatch::{Algorithm, BatchWindow};
+use crate::emissions::EmissionFactors;
+use crate::events::{self, Event};
+use crate::kpi::{KpiTracker, Kpis};
+use crate::metrics::{GaugeGuard, METRICS};
+use crate::models::Scenario;
+
+/// How many messages a slow subscriber may fall behind before it has to resume from the history
+const BROADCAST_CAPACITY: usize = 256;
+/// How many recent messages are kept for clients that reconnect
+const HISTORY_CAPACITY: usize = 1024;
+/// How long a session keeps running without anyone watching, so clients can reconnect
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many finished sessions are kept for inspection, older ones are forgotten
+const FINISHED_CAPACITY: usize = 32;
+/// How long shutdown waits for the simulations to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a session tells its subscribers
+#[derive(Clone)]
+pub enum Broadcast {
+    /// The scenario after a tick, subscribers derive snapshots and deltas from it
+    Tick(Arc<Scenario>),
+    Event(Arc<Event>),
+}
+
+/// A message of the session's stream together with its position in it
+#[derive(Clone)]
+pub struct Published {
+    pub seq: u64,
+    pub broadcast: Broadcast,
+}
+
+/// Where a new subscriber picks up the stream
+pub enum Resume {
+    /// Everything the client missed, `base` is the state it had when it left
+    Missed {
+        base: Arc<Scenario>,
+        missed: Vec<Published>,
+    },
+    /// The client is new or too far behind, it starts over with the current state
+    Snapshot {
+        seq: u64,
+        scenario: Arc<Scenario>,
+        kpis: Option<Box<Kpis>>,
+    },
+}
+
+/// A command for the simulation task together with where to send the reply
+pub struct SessionCommand {
+    pub message: CommandMessage,
+    pub reply: oneshot::Sender<Event>,
+}
+
+impl SessionCommand {
+    pub fn reply(self, event: Event) {
+        // The client may have disconnected in the meantime
+        let _ = self.reply.send(event);
+    }
+}
+
+struct History {
+    seq: u64,
+    latest: Arc<Scenario>,
+    /// The state before the oldest message in `recent`
+    base: Arc<Scenario>,
+    recent: VecDeque<Published>,
+    tracker: KpiTracker,
+    /// Set once the run is over
+    kpis: Option<Kpis>,
+}
+
+impl History {
+    fn push(&mut self, broadcast: Broadcast) -> Published {
+        self.seq += 1;
+        let published = Published {
+            seq: self.seq,
+            broadcast,
+        };
+
+        self.recent.push_back(published.clone());
+        if self.recent.len() > HISTORY_CAPACITY {
+            if let Some(Published {
+                broadcast: Broadcast::Tick(scenario),
+                ..
+            }) = self.recent.pop_front()
+            {
+                self.base = scenario;
+            }
+        }
+        published
+    }
+}
+
+/// One running (or finished) simulation that any number of clients can watch
+pub struct Session {
+    pub id: String,
+    pub scenario_id: String,
+    pub replay: bool,
+    /// Milliseconds since the Unix epoch
+    pub started_at: u64,
+    state: Mutex<RunState>,
+    broadcast: broadcast::Sender<Published>,
+    commands: mpsc::Sender<SessionCommand>,
+    history: Mutex<History>,
+    viewers: AtomicUsize,
+    /// How the run was started, `None` for replays
+    settings: Mutex<Option<RunSettings>>,
+    /// Stops the simulation once nobody is watching anymore, or the server shuts down
+    cancel: CancellationToken,
+    /// Cancelled once the simulation ended and published its last message
+    done: CancellationToken,
+}
+
+/// How far a session got
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RunState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed { message: String },
+}
+
+/// What a live run was started with. Viewers joining it later get these, not their own.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSettings {
+    pub speed: f64,
+    /// The dispatcher deciding right now, it can be switched during the run
+    pub algorithm: Algorithm,
+    pub record: bool,
+    pub arrival_rate: Option<f64>,
+    pub arrival_list: bool,
+    pub shift_list: bool,
+    pub charger_list: bool,
+}
+
+/// What the REST API tells about a session
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RunInfo {
+    pub id: String,
+    pub scenario_id: String,
+    pub replay: bool,
+    pub started_at: u64,
+    #[serde(flatten)]
+    pub state: RunState,
+    pub viewers: usize,
+    /// The runner's status of the scenario
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<RunSettings>,
+}
+
+/// Counts a connected client for as long as it is alive
+pub struct Viewer(Arc<Session>);
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        if self.0.viewers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.stop_when_idle();
+        }
+    }
+}
+
+impl Session {
+    /// Carries the session and scenario ids into everything logged for this session
+    pub fn span(&self) -> Span {
+        info_span!("session", id = %self.id, scenario_id = %self.scenario_id)
+    }
+
+    pub fn join(self: &Arc<Self>) -> Viewer {
+        self.viewers.fetch_add(1, Ordering::SeqCst);
+        Viewer(self.clone())
+    }
+
+    /// The final KPIs once the run is over, the ones so far before that
+    pub fn kpis(&self) -> Kpis {
+        let history = self.history.lock().unwrap();
+        history
+            .kpis
+            .clone()
+            .unwrap_or_else(|| history.tracker.kpis(&history.latest))
+    }
+
+    pub fn state(&self) -> RunState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn info(&self) -> RunInfo {
+        RunInfo {
+            id: self.id.clone(),
+            scenario_id: self.scenario_id.clone(),
+            replay: self.replay,
+            started_at: self.started_at,
+            state: self.state(),
+            viewers: self.viewers.load(Ordering::SeqCst),
+            status: self.latest().status.clone(),
+            settings: self.settings(),
+        }
+    }
+
+    pub fn settings(&self) -> Option<RunSettings> {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set_settings(&self, settings: RunSettings) {
+        *self.settings.lock().unwrap() = Some(settings);
+    }
+
+    /// Keeps the settings up to date when a client switches the dispatcher
+    pub fn set_algorithm(&self, algorithm: Algorithm) {
+        if let Some(settings) = self.settings.lock().unwrap().as_mut() {
+            settings.algorithm = algorithm;
+        }
+    }
+
+    /// Stops the simulation, clients keep the session until they leave
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Resolves once the simulation was told to stop
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Resolves once the simulation ended, everything it published is in the stream by then
+    pub async fn done(&self) {
+        self.done.cancelled().await
+    }
+
+    /// Cancels the simulation unless a client joins within [`IDLE_TIMEOUT`]
+    fn stop_when_idle(self: &Arc<Self>) {
+        let session = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sleep(IDLE_TIMEOUT) => {}
+                _ = session.cancel.cancelled() => return,
+            }
+            if session.viewers.load(Ordering::SeqCst) == 0 {
+                info!(
+                    "Nobody is watching session {} anymore, stopping it",
+                    session.id
+                );
+                session.cancel.cancel();
+            }
+        });
+    }
+
+    /// Subscribes to the stream, continuing after `last_seq` if the client got that far before
+    pub fn subscribe(&self, last_seq: Option<u64>) -> (broadcast::Receiver<Published>, Resume) {
+        // Holding the history lock means nothing gets published in between
+        let history = self.history.lock().unwrap();
+        let receiver = self.broadcast.subscribe();
+
+        let oldest = history.recent.front().map_or(history.seq + 1, |p| p.seq);
+        let resume = match last_seq {
+            Some(last) if last <= history.seq && last + 1 >= oldest => Resume::Missed {
+                base: history
+                    .recent
+                    .iter()
+                    .take_while(|p| p.seq <= last)
+                    .filter_map(|p| match &p.broadcast {
+                        Broadcast::Tick(scenario) => Some(scenario.clone()),
+                        Broadcast::Event(_) => None,
+                    })
+                    .last()
+                    .unwrap_or_else(|| history.base.clone()),
+                missed: history
+                    .recent
+                    .iter()
+                    .filter(|p| p.seq > last)
+                    .cloned()
+                    .collect(),
+            },
+            _ => Resume::Snapshot {
+                seq: history.seq,
+                scenario: history.latest.clone(),
+                kpis: history.kpis.clone().map(Box::new),
+            },
+        };
+
+        (receiver, resume)
+    }
+
+    /// The scenario after the most recent tick
+    pub fn latest(&self) -> Arc<Scenario> {
+        self.history.lock().unwrap().latest.clone()
+    }
+
+    /// Publishes what happened since the last tick, followed by the new state.
+    /// `at` is how far into the run the tick happened, on the runner's clock,
+    /// `dispatcher` the one that decides what happens next.
+    pub fn publish_tick(&self, scenario: Scenario, at: Duration, dispatcher: Option<&str>) {
+        let scenario = Arc::new(scenario);
+        let mut history = self.history.lock().unwrap();
+        history.tracker.observe(at, dispatcher, &scenario);
+
+        for event in events::diff(&history.latest, &scenario) {
+            let published = history.push(Broadcast::Event(Arc::new(event)));
+            // Nobody might be watching, which is fine
+            let _ = self.broadcast.send(published);
+        }
+
+        history.latest = scenario.clone();
+        let published = history.push(Broadcast::Tick(scenario));
+        let _ = self.broadcast.send(published);
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut history = self.history.lock().unwrap();
+        if let Event::Finished { kpis } = &event {
+            history.kpis = Some(kpis.clone());
+        }
+        let published = history.push(Broadcast::Event(Arc::new(event)));
+        let _ = self.broadcast.send(published);
+    }
+
+    /// Hands a command to the simulation task, the reply arrives on the returned channel
+    pub async fn command(&self, message: CommandMessage) -> oneshot::Receiver<Event> {
+        let (reply, receiver) = oneshot::channel();
+        let id = message.id.clone();
+
+        if let Err(mpsc::error::SendError(command)) =
+            self.commands.send(SessionCommand { message, reply }).await
+        {
+            command.reply(Event::error(id, "The simulation is not running"));
+        }
+        receiver
+    }
+}
+
+/// Keeps other clients from starting the same scenario, see [`SessionRegistry::lock_start`]
+pub struct StartGuard {
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    starting: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    scenario_id: String,
+}
+
+impl Drop for StartGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+        // Waiting clients hold the lock too, the last one out removes it
+        let mut starting = self.starting.lock().unwrap();
+        if starting
+            .get(&self.scenario_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            starting.remove(&self.scenario_id);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    by_id: HashMap<String, Arc<Session>>,
+    /// Running, non-replay sessions by scenario id
+    live: HashMap<String, String>,
+    /// Ids of finished sessions, oldest first
+    finished: VecDeque<String>,
+}
+
+/// Owns all sessions, so runs continue no matter which clients come and go
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<Sessions>>,
+    /// Held while a scenario's session is being set up, so it is never initialized twice.
+    /// Other scenarios can start in the meantime.
+    starting: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Parent of every session's token
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+    emissions: Arc<EmissionFactors>,
+    batch_window: BatchWindow,
+    battery_model: BatteryModel,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        emissions: EmissionFactors,
+        batch_window: BatchWindow,
+        battery_model: BatteryModel,
+    ) -> Self {
+        SessionRegistry {
+            emissions: Arc::new(emissions),
+            batch_window,
+            battery_model,
+            ..SessionRegistry::default()
+        }
+    }
+
+    pub fn emissions(&self) -> &EmissionFactors {
+        &self.emissions
+    }
+
+    /// How the runs started here batch their dispatching with [`Algorithm::Batched`]
+    pub fn batch_window(&self) -> BatchWindow {
+        self.batch_window
+    }
+
+    pub fn battery_model(&self) -> BatteryModel {
+        self.battery_model
+    }
+
+    /// Serializes session creation per scenario, hold the guard until
+    /// [`SessionRegistry::start`] returned
+    pub async fn lock_start(&self, scenario_id: &str) -> StartGuard {
+        let lock = self
+            .starting
+            .lock()
+            .unwrap()
+            .entry(scenario_id.to_string())
+            .or_default()
+            .clone();
+
+        StartGuard {
+            guard: Some(lock.lock_owned().await),
+            starting: self.starting.clone(),
+            scenario_id: scenario_id.to_string(),
+        }
+    }
+
+    /// All sessions, oldest first
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.started_at);
+        sessions
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().by_id.get(session_id).cloned()
+    }
+
+    /// The running session of a scenario, if there is one
+    pub fn find_live(&self, scenario_id: &str) -> Option<Arc<Session>> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .live
+            .get(scenario_id)
+            .and_then(|id| sessions.by_id.get(id))
+            .cloned()
+    }
+
+    /// Creates a session and spawns its simulation task.
+    /// Replays are not shared by scenario id, they can only be joined by their session id.
+    pub fn start<F, Fut>(
+        &self,
+        initial_scenario: Scenario,
+        replay: bool,
+        simulation: F,
+    ) -> Arc<Session>
+    where
+        F: FnOnce(Arc<Session>, mpsc::Receiver<SessionCommand>) -> Fut,
+        Fut: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+    {
+        let (broadcast, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (commands, command_receiver) = mpsc::channel(16);
+
+        let initial_scenario = Arc::new(initial_scenario);
+        let session = Arc::new(Session {
+            id: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect(),
+            scenario_id: initial_scenario.id.clone(),
+            replay,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            state: Mutex::new(RunState::Running),
+            broadcast,
+            commands,
+            history: Mutex::new(History {
+                seq: 0,
+                tracker: {
+                    let mut tracker = KpiTracker::new(self.emissions.clone());
+                    tracker.observe(Duration::ZERO, None, &initial_scenario);
+                    tracker
+                },
+                latest: initial_scenario.clone(),
+                base: initial_scenario,
+                recent: VecDeque::new(),
+                kpis: None,
+            }),
+            viewers: AtomicUsize::new(0),
+            settings: Mutex::new(None),
+            cancel: self.shutdown.child_token(),
+            done: CancellationToken::new(),
+        });
+
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.by_id.insert(session.id.clone(), session.clone());
+            if !replay {
+                sessions
+                    .live
+                    .insert(session.scenario_id.clone(), session.id.clone());
+            }
+        }
+
+        info!(
+            "Started session {} for scenario {}",
+            session.id, session.scenario_id
+        );
+
+        let simulation = simulation(session.clone(), command_receiver);
+        let registry = self.clone();
+        let finished = session.clone();
+        let span = session.span();
+        self.tasks.spawn(
+            async move {
+                let _running = GaugeGuard::new(&METRICS.active_runs);
+                let state = tokio::select! {
+                    result = simulation => match result {
+                        Ok(()) => RunState::Finished,
+                        Err(e) => {
+                            error!(
+                                "Simulation of session {} for scenario {} failed: {}",
+                                finished.id, finished.scenario_id, e
+                            );
+                            let message = format!("The simulation failed: {}", e);
+                            finished.publish(Event::error(None, message.clone()));
+                            RunState::Failed { message }
+                        }
+                    },
+                    _ = finished.cancel.cancelled() => {
+                        info!("Session {} was cancelled", finished.id);
+                        finished.publish(Event::error(None, "The simulation was stopped"));
+                        RunState::Cancelled
+                    }
+                };
+
+                *finished.state.lock().unwrap() = state;
+                registry.finish(&finished);
+                finished.done.cancel();
+            }
+            .instrument(span),
+        );
+
+        // Don't run forever if the client never connects
+        session.stop_when_idle();
+        session
+    }
+
+    /// Stops all simulations and waits for them to wind down
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.tasks.close();
+        if timeout(SHUTDOWN_TIMEOUT, self.tasks.wait()).await.is_err() {
+            warn!("Some simulations did not stop in time");
+        }
+    }
+
+    /// The session stays available for inspection, but new clients start a new run.
+    /// Only the last [`FINISHED_CAPACITY`] finished sessions are kept, each holds its history.
+    fn finish(&self, session: &Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.live.get(&session.scenario_id) == Some(&session.id) {
+            sessions.live.remove(&session.scenario_id);
+        }
+        sessions.finished.push_back(session.id.clone());
+        while sessions.finished.len() > FINISHED_CAPACITY {
+            if let Some(oldest) = sessions.finished.pop_front() {
+                sessions.by_id.remove(&oldest);
+            }
+        }
+        info!("Session {} finished", session.id);
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[tokio::test]
+async fn test_late_joiners_share_the_running_session() {
+    let registry = SessionRegistry::default();
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "CREATED".to_string(),
+        ..Default::default()
+    };
+    let (tick, ticked) = oneshot::channel::<()>();
+
+    let session = registry.start(
+        scenario.clone(),
+        false,
+        move |session, _commands| async move {
+            let mut running = scenario;
+            running.status = "RUNNING".to_string();
+            session.publish_tick(running, Duration::ZERO, None);
+            let _ = tick.send(());
+            // Stay alive like a real run
+            std::future::pending::<()>().await;
+            Ok(())
+        },
+    );
+    let (mut early, _) = session.subscribe(None);
+    ticked.await.unwrap();
+
+    assert!(matches!(
+        early.recv().await,
+        Ok(Published { seq: 1, broadcast: Broadcast::Tick(s) }) if s.status == "RUNNING"
+    ));
+
+    let late = registry.find_live("s1").unwrap();
+    assert_eq!(late.id, session.id);
+    assert_eq!(late.latest().status, "RUNNING");
+    assert!(registry.find_live("s2").is_none());
+}
+
+#[tokio::test]
+async fn test_reconnecting_clients_resume_after_their_last_message() {
+    let registry = SessionRegistry::default();
+    let mut scenario = Scenario {
+        id: "s1".to_string(),
+        status: "CREATED".to_string(),
+        ..Default::default()
+    };
+    let session = registry.start(scenario.clone(), true, |_session, _commands| async {
+        std::future::pending::<()>().await;
+        Ok(())
+    });
+
+    scenario.status = "RUNNING".to_string();
+    session.publish_tick(scenario.clone(), Duration::from_secs(1), None);
+    session.publish(Event::error(None, "hiccup"));
+    scenario.status = "FINISHED".to_string();
+    session.publish_tick(scenario, Duration::from_secs(2), None);
+
+    let Resume::Missed { base, missed } = session.subscribe(Some(1)).1 else {
+        panic!("Expected the missed messages");
+    };
+    assert_eq!(base.status, "RUNNING");
+    assert_eq!(missed.iter().map(|p| p.seq).collect::<Vec<_>>(), [2, 3]);
+
+    let Resume::Missed { base, missed } = session.subscribe(Some(0)).1 else {
+        panic!("Expected the missed messages");
+    };
+    assert_eq!(base.status, "CREATED");
+    assert_eq!(missed.len(), 3);
+
+    // A sequence number from another session starts over
+    assert!(matches!(
+        session.subscribe(Some(42)).1,
+        Resume::Snapshot { seq: 3, .. }
+    ));
+    assert!(registry.find_live("s1").is_none());
+}
+
+#[tokio::test]
+async fn test_shutdown_cancels_running_simulations() {
+    let registry = SessionRegistry::default();
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        ..Default::default()
+    };
+    let session = registry.start(scenario, false, |_session, _commands| async {
+        std::future::pending::<()>().await;
+        Ok(())
+    });
+    let (mut receiver, _) = session.subscribe(None);
+
+    registry.shutdown().await;
+    // By the time the session is done, its last message can be read without waiting
+    session.done().await;
+
+    assert!(matches!(
+        receiver.try_recv(),
+        Ok(Published { broadcast: Broadcast::Event(event), .. })
+            if matches!(*event, Event::Error { id: None, .. })
+    ));
+    assert!(registry.find_live("s1").is_none());
+}
+
+#[tokio::test]
+async fn test_scenarios_start_independently() {
+    let registry = SessionRegistry::default();
+
+    let slow = registry.lock_start("s1").await;
+    // Another scenario doesn't wait for s1, the same one does
+    let other = timeout(Duration::from_secs(1), registry.lock_start("s2")).await;
+    assert!(other.is_ok());
+    let same = timeout(Duration::from_millis(50), registry.lock_start("s1")).await;
+    assert!(same.is_err());
+
+    drop(other);
+    drop(slow);
+    assert!(registry.starting.lock().unwrap().is_empty());
+    let _again = timeout(Duration::from_secs(1), registry.lock_start("s1"))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_old_finished_sessions_are_forgotten() {
+    let registry = SessionRegistry::default();
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        ..Default::default()
+    };
+    let first = registry.start(scenario.clone(), true, |_session, _commands| async {
+        Ok(())
+    });
+    for _ in 0..FINISHED_CAPACITY + 9 {
+        registry.start(scenario.clone(), true, |_session, _commands| async {
+            Ok(())
+        });
+    }
+    let running = registry.start(scenario, false, |_session, _commands| async {
+        std::future::pending::<()>().await;
+        Ok(())
+    });
+    assert_eq!(registry.list().len(), FINISHED_CAPACITY + 11);
+
+    // Let the replays finish
+    while registry.list().len() > FINISHED_CAPACITY + 1 {
+        tokio::task::yield_now().await;
+    }
+    assert!(registry.get(&first.id).is_none());
+    assert!(registry.get(&running.id).is_some());
+    registry.shutdown().await;
+}
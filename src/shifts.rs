@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+use crate::models::Scenario;
+
+/// One row of a shift list
+#[derive(Deserialize)]
+struct ShiftRow {
+    vehicle_id: String,
+    /// Seconds of the runner's clock after launch
+    start: f64,
+    end: f64,
+    /// Semicolon separated `start-end` pairs, e.g. `1800-2100;5400-5700`
+    #[serde(default)]
+    breaks: String,
+}
+
+/// When a driver works, all times in seconds of the runner's clock after launch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shift {
+    pub start: f64,
+    pub end: f64,
+    pub breaks: Vec<(f64, f64)>,
+}
+
+impl Shift {
+    /// Seconds the vehicle may still drive before it has to stop, zero while off duty
+    pub fn remaining(&self, at: f64) -> f64 {
+        if at < self.start || at >= self.end {
+            return 0.0;
+        }
+        self.breaks
+            .iter()
+            .filter(|(_, end)| *end > at)
+            .map(|(start, _)| (start - at).max(0.0))
+            .fold(self.end - at, f64::min)
+    }
+}
+
+/// Returns where the shift list of a scenario is stored
+pub fn shift_list_path(inputs_dir: &Path, scenario_id: &str) -> anyhow::Result<PathBuf> {
+    crate::scenario_io::input_path(inputs_dir, scenario_id, "shifts.csv")
+}
+
+/// The shifts of a fleet. Vehicles without a shift are always on duty.
+#[derive(Debug, Clone, Default)]
+pub struct ShiftSchedule {
+    shifts: HashMap<String, Shift>,
+}
+
+impl ShiftSchedule {
+    /// Reads a CSV file with `vehicle_id`, `start`, `end` and `breaks` columns
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut shifts = HashMap::new();
+        for row in csv::Reader::from_reader(content.as_bytes()).deserialize() {
+            let row: ShiftRow = row?;
+            let breaks = row
+                .breaks
+                .split(';')
+                .filter(|b| !b.trim().is_empty())
+                .map(|b| {
+                    let (start, end) = b
+                        .split_once('-')
+                        .ok_or_else(|| anyhow!("Break '{}' is not a start-end pair", b))?;
+                    Ok((start.trim().parse()?, end.trim().parse()?))
+                })
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid breaks of vehicle {}", row.vehicle_id))?;
+            shifts.insert(
+                row.vehicle_id,
+                Shift {
+                    start: row.start,
+                    end: row.end,
+                    breaks,
+                },
+            );
+        }
+        Ok(ShiftSchedule { shifts })
+    }
+
+    /// Tells the dispatcher how long every vehicle may still drive `at` into the run.
+    /// The runner knows nothing about shifts, so idle vehicles off duty are marked
+    /// unavailable here and simply not given any trip.
+    pub fn apply(&self, mut scenario: Scenario, at: Duration) -> Scenario {
+        let at = at.as_secs_f64();
+        for vehicle in &mut scenario.vehicles {
+            let Some(shift) = self.shifts.get(&vehicle.id) else {
+                continue;
+            };
+            let remaining = shift.remaining(at);
+            vehicle.shift_remaining = Some(remaining);
+            if remaining <= 0.0 && vehicle.customer_id.is_none() {
+                vehicle.is_available = false;
+            }
+        }
+        scenario
+    }
+}
+
+/*=================TESTS===============================*/
+
+#[test]
+fn test_vehicles_stop_for_breaks_and_shift_end() {
+    use crate::models::{Customer, Vehicle};
+
+    let path = std::env::temp_dir().join(format!("shifts-test-{}.csv", std::process::id()));
+    fs::write(
+        &path,
+        "vehicle_id,start,end,breaks\nv1,0,3600,1800-2100\nv2,600,3600,\n",
+    )
+    .unwrap();
+    let schedule = ShiftSchedule::load(&path).unwrap();
+    fs::remove_file(path).unwrap();
+
+    let vehicle = |id: &str| Vehicle {
+        id: id.to_string(),
+        coord_x: 48.0,
+        coord_y: 11.5,
+        is_available: true,
+        ..Default::default()
+    };
+    let scenario = Scenario {
+        id: "s1".to_string(),
+        status: "RUNNING".to_string(),
+        vehicles: vec![vehicle("v1"), vehicle("v2"), vehicle("v3")],
+        ..Default::default()
+    };
+    let at = |secs| schedule.apply(scenario.clone(), Duration::from_secs(secs));
+
+    let early = at(300);
+    let remaining: Vec<_> = early.vehicles.iter().map(|v| v.shift_remaining).collect();
+    assert_eq!(remaining, [Some(1500.0), Some(0.0), None]);
+    assert!(!early.vehicles[1].is_available);
+    assert_eq!(at(2000).vehicles[0].shift_remaining, Some(0.0));
+    assert_eq!(at(2400).vehicles[0].shift_remaining, Some(1200.0));
+    assert!(!at(3600).vehicles[0].is_available);
+
+    // Over twenty kilometres take longer than the 1500 seconds left before the break
+    let far = Customer {
+        id: "c1".to_string(),
+        coord_x: 48.0,
+        coord_y: 11.5,
+        destination_x: Some(48.2),
+        destination_y: Some(11.5),
+        awaiting_service: true,
+        ..Default::default()
+    };
+    assert!(!crate::matching::can_serve(&early.vehicles[0], &far));
+    assert!(crate::matching::can_serve(&early.vehicles[2], &far));
+}
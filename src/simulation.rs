@@ -0,0 +1,513 @@
+use std::{
+    cmp::min,
+    collections::HashSet,
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::mpsc::Receiver, time::sleep};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::arrivals::ArrivalSchedule;
+use crate::battery::Batteries;
+use crate::control::{validate_assignment, Command};
+use crate::dispatch::{self, BatchWindow, Dispatcher};
+use crate::error::ClientError;
+use crate::events::Event;
+use crate::metrics::METRICS;
+use crate::models::{
+    Charger, Customer, Scenario, UpdateScenario, UpdateScenarioResponse, UpdateVehicle,
+};
+use crate::rebalance::Rebalancer;
+use crate::recording::{Recorder, Replay};
+use crate::runner::RunnerClient;
+use crate::session::{RunSettings, Session, SessionCommand};
+use crate::shifts::ShiftSchedule;
+
+/// How many updates in a row may contain rejected assignments before the run is given up
+const MAX_FAILED_UPDATES: u32 = 5;
+
+/// How often the scenario is fetched again while the runner is unreachable
+const OUTAGE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the runner may be unreachable before the run is given up
+const MAX_OUTAGE: Duration = Duration::from_secs(300);
+
+/// What the runner doesn't know about a run, laid over every scenario it reports
+pub(crate) struct Timetable {
+    pub arrivals: ArrivalSchedule,
+    pub shifts: ShiftSchedule,
+    pub chargers: Vec<Charger>,
+    pub batteries: Batteries,
+}
+
+impl Timetable {
+    /// The scenario as the dispatcher and clients see it `at` into the run
+    pub fn apply(&mut self, mut scenario: Scenario, at: Duration) -> Scenario {
+        scenario.chargers.clone_from(&self.chargers);
+        let scenario = self.shifts.apply(self.arrivals.visible(scenario, at), at);
+        self.batteries.apply(scenario, at)
+    }
+}
+
+/// A manual assignment waiting for the next update, replied to once the runner answered
+struct PendingAssignment {
+    command: SessionCommand,
+    assignment: UpdateVehicle,
+}
+
+/// State clients can change with commands while a scenario is running
+#[derive(Default)]
+struct RunControl {
+    paused: bool,
+    manual: Vec<PendingAssignment>,
+}
+
+/// Applies a client command to a live run. Manual assignments are only
+/// acknowledged once the runner accepted them.
+fn handle_command(
+    command: SessionCommand,
+    session: &Session,
+    scenario: &Scenario,
+    control: &mut RunControl,
+    dispatcher: &mut Box<dyn Dispatcher>,
+    batch_window: BatchWindow,
+) {
+    let message = &command.message;
+    let reply = match &message.command {
+        Command::Pause => {
+            control.paused = true;
+            Event::ack(message)
+        }
+        Command::Resume => {
+            control.paused = false;
+            Event::ack(message)
+        }
+        Command::SetSpeed { .. } => Event::error(
+            message.id.clone(),
+            "The runner's speed cannot be changed after the scenario was launched",
+        ),
+        Command::SetDispatcher { algorithm } => {
+            info!("Switching scenario {} to {:?}", scenario.id, algorithm);
+            *dispatcher = dispatch::for_algorithm(*algorithm, batch_window);
+            session.set_algorithm(*algorithm);
+            Event::ack(message)
+        }
+        Command::Snapshot => Event::Snapshot {
+            id: message.id.clone(),
+            scenario: scenario.clone(),
+        },
+        // Each connection keeps track of its own keyframes
+        Command::Resync => Event::ack(message),
+        Command::Assign {
+            vehicle_id,
+            customer_id,
+        } => {
+            let already_queued = control.manual.iter().any(|m| {
+                &m.assignment.id == vehicle_id || &m.assignment.customer_id == customer_id
+            });
+
+            match validate_assignment(scenario, vehicle_id, customer_id) {
+                Ok(()) if !already_queued => {
+                    let assignment = UpdateVehicle {
+                        id: vehicle_id.clone(),
+                        customer_id: customer_id.clone(),
+                    };
+                    control.manual.push(PendingAssignment {
+                        command,
+                        assignment,
+                    });
+                    return;
+                }
+                Ok(()) => Event::error(
+                    message.id.clone(),
+                    "The vehicle or customer already has a pending assignment",
+                ),
+                Err(e) => Event::error(message.id.clone(), e),
+            }
+        }
+    };
+
+    command.reply(reply);
+}
+
+/// Announces the assignments from an update that the runner accepted
+fn publish_assignments(
+    session: &Session,
+    update: &UpdateScenario,
+    response: &UpdateScenarioResponse,
+    manual: &[PendingAssignment],
+) {
+    for assignment in &update.vehicles {
+        if response.failed_to_update.contains(&assignment.id) {
+            continue;
+        }
+
+        session.publish(Event::Assignment {
+            vehicle_id: assignment.id.clone(),
+            customer_id: assignment.customer_id.clone(),
+            manual: manual.iter().any(|m| m.assignment.id == assignment.id),
+        });
+    }
+}
+
+/// Tells clients when the runner starts or stops failing
+fn report_health(
+    session: &Session,
+    runner_client: &RunnerClient,
+    degraded: &mut bool,
+    error: Option<&ClientError>,
+) {
+    let now_degraded = runner_client.is_degraded();
+    if now_degraded == *degraded {
+        return;
+    }
+
+    *degraded = now_degraded;
+    session.publish(if now_degraded {
+        Event::Degraded {
+            service: "runner",
+            message: error.map_or_else(
+                || "Calls to the runner keep failing".to_string(),
+                ToString::to_string,
+            ),
+        }
+    } else {
+        Event::Recovered { service: "runner" }
+    });
+}
+
+pub(crate) async fn scenario_simulator(
+    runner_client: RunnerClient,
+    session: Arc<Session>,
+    mut commands: Receiver<SessionCommand>,
+    settings: RunSettings,
+    batch_window: BatchWindow,
+    mut recorder: Option<Recorder>,
+    mut timetable: Timetable,
+) -> Result<(), Box<dyn Error>> {
+    let mut scenario = (*session.latest()).clone();
+    let scenario_id = scenario.id.clone();
+
+    let speed = settings.speed;
+    let mut dispatcher = dispatch::for_algorithm(settings.algorithm, batch_window);
+    let mut control = RunControl::default();
+    let mut failed_updates = 0;
+    let mut degraded = false;
+    let mut rebalancer = Rebalancer::new(&scenario);
+    let mut update_failing_since = None;
+
+    let scenario_launch = match runner_client.launch_scenario(&scenario_id, speed).await {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(format!("Failed to launch scenario: {}", e).into());
+        }
+    };
+
+    info!("Scenario launched: {:?}", scenario_launch);
+    let launched = Instant::now();
+    // The runner's speed is how much real time one second of the scenario takes
+    let run_time = || launched.elapsed().div_f64(speed);
+
+    let mut tick: u64 = 0;
+    while scenario.end_time.is_none() {
+        let span = info_span!("tick", tick, dispatcher = dispatcher.name());
+        async {
+            let tick_started = Instant::now();
+            while let Ok(command) = commands.try_recv() {
+                handle_command(
+                    command,
+                    &session,
+                    &scenario,
+                    &mut control,
+                    &mut dispatcher,
+                    batch_window,
+                );
+            }
+
+            if control.paused && control.manual.is_empty() {
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record(run_time(), dispatcher.name(), &scenario, None, None);
+                }
+            } else {
+                let mut manual = std::mem::take(&mut control.manual);
+
+                let mut assignments = if control.paused {
+                    UpdateScenario { vehicles: vec![] }
+                } else {
+                    let _timer = METRICS
+                        .dispatch_duration
+                        .with_label_values(&[dispatcher.name()])
+                        .start_timer();
+                    dispatcher.dispatch(&scenario)
+                };
+                // Manual assignments win over whatever the dispatcher decided
+                assignments.vehicles.retain(|a| {
+                    !manual.iter().any(|m| {
+                        m.assignment.id == a.id || m.assignment.customer_id == a.customer_id
+                    })
+                });
+                assignments
+                    .vehicles
+                    .extend(manual.iter().map(|m| m.assignment.clone()));
+
+                debug_assert!(
+                    assignments.vehicles.len()
+                        <= min(scenario.vehicles.len(), scenario.customers.len())
+                );
+                let update = match runner_client
+                    .update_scenario(&scenario_id, &assignments)
+                    .await
+                {
+                    Ok(update) => Some(update),
+                    // Like a failed fetch, a failed update waits for the runner to come back,
+                    // but only until MAX_OUTAGE. The scenario fetched below shows whether the
+                    // runner took the assignments, so the next tick plans again from there.
+                    Err(e) if e.is_transient() => {
+                        let failing_since = *update_failing_since.get_or_insert_with(Instant::now);
+                        if failing_since.elapsed() >= MAX_OUTAGE {
+                            return Err(format!(
+                                "The runner failed to take assignments for {} seconds: {}",
+                                failing_since.elapsed().as_secs(),
+                                e
+                            )
+                            .into());
+                        }
+                        warn!("Failed to update scenario {}: {}", scenario_id, e);
+                        report_health(&session, &runner_client, &mut degraded, Some(&e));
+                        dispatcher.replan();
+                        // Manual assignments are sent again with the next update
+                        control.manual = std::mem::take(&mut manual);
+                        sleep(OUTAGE_POLL_INTERVAL).await;
+                        None
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                if let Some(update) = update {
+                    update_failing_since = None;
+
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record(
+                            run_time(),
+                            dispatcher.name(),
+                            &scenario,
+                            Some(&assignments),
+                            Some(&update),
+                        );
+                    }
+
+                    dispatcher.on_update(&update);
+                    METRICS
+                        .failed_vehicle_updates
+                        .with_label_values(&[dispatcher.name()])
+                        .inc_by(update.failed_to_update.len() as u64);
+
+                    // The runner can reject assignments when our view of the scenario is stale.
+                    // The state is fetched again below, so re-planning on it usually fixes that.
+                    let rejected: Vec<&String> = update
+                        .failed_to_update
+                        .iter()
+                        .filter(|v| !manual.iter().any(|m| &m.assignment.id == *v))
+                        .collect();
+                    if rejected.is_empty() {
+                        failed_updates = 0;
+                    } else {
+                        failed_updates += 1;
+                        warn!(
+                            "The runner rejected assignments of scenario {} for {:?}, {} in a row",
+                            scenario_id, rejected, failed_updates
+                        );
+                        if failed_updates >= MAX_FAILED_UPDATES {
+                            return Err(format!(
+                                "The runner rejected assignments {} times in a row",
+                                failed_updates
+                            )
+                            .into());
+                        }
+                        dispatcher.replan();
+                    }
+
+                    publish_assignments(&session, &assignments, &update, &manual);
+
+                    // Only routes whose first pickup the runner took are worth announcing
+                    let mut routes = dispatcher.take_routes();
+                    routes.retain(|route| {
+                        assignments.vehicles.iter().any(|a| {
+                            a.id == route.vehicle_id
+                                && route.stops.first().map(|s| &s.customer_id)
+                                    == Some(&a.customer_id)
+                        }) && !update.failed_to_update.contains(&route.vehicle_id)
+                    });
+                    if !routes.is_empty() {
+                        session.publish(Event::SharedRides { routes });
+                    }
+
+                    for pending in manual {
+                        let id = pending.command.message.id.clone();
+                        let reply = if update.failed_to_update.contains(&pending.assignment.id) {
+                            Event::error(
+                                id,
+                                format!(
+                                    "The runner rejected the assignment of {}",
+                                    pending.assignment.id
+                                ),
+                            )
+                        } else {
+                            Event::Ack {
+                                id,
+                                command: "assign",
+                            }
+                        };
+                        pending.command.reply(reply);
+                    }
+                }
+            }
+
+            // Losing the runner for a while shouldn't end the run, but headless runs have
+            // nobody to cancel them, so an outage longer than MAX_OUTAGE does
+            let outage_started = Instant::now();
+            let current = loop {
+                match runner_client.get_scenario(&scenario_id).await {
+                    Ok(current) => break timetable.apply(current, run_time()),
+                    Err(e) if e.is_transient() && outage_started.elapsed() >= MAX_OUTAGE => {
+                        return Err(format!(
+                            "The runner was unreachable for {} seconds: {}",
+                            outage_started.elapsed().as_secs(),
+                            e
+                        )
+                        .into());
+                    }
+                    Err(e) if e.is_transient() => {
+                        warn!("Failed to fetch scenario {}: {}", scenario_id, e);
+                        report_health(&session, &runner_client, &mut degraded, Some(&e));
+                        sleep(OUTAGE_POLL_INTERVAL).await;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            report_health(&session, &runner_client, &mut degraded, None);
+
+            let known: HashSet<&str> = scenario.customers.iter().map(|c| c.id.as_str()).collect();
+            let arrived: Vec<Customer> = current
+                .customers
+                .iter()
+                .filter(|c| !known.contains(c.id.as_str()))
+                .cloned()
+                .collect();
+            if !arrived.is_empty() {
+                info!("{} customers arrived", arrived.len());
+                dispatcher.on_arrivals(&arrived);
+            }
+            scenario = current;
+
+            session.publish_tick(scenario.clone(), run_time(), Some(dispatcher.name()));
+            if let Some(moves) = rebalancer.update(&scenario) {
+                session.publish(Event::Reposition { moves });
+            }
+            let detours = timetable.batteries.take_detours();
+            if !detours.is_empty() {
+                session.publish(Event::Charging { detours });
+            }
+            METRICS
+                .tick_duration
+                .with_label_values(&[dispatcher.name()])
+                .observe(tick_started.elapsed().as_secs_f64());
+
+            // TODO: Maybe not?
+            // sleep(std::time::Duration::from_millis(100)).await;
+            Ok::<_, Box<dyn Error>>(())
+        }
+        .instrument(span)
+        .await?;
+        tick += 1;
+    }
+
+    if let Some(mut recorder) = recorder {
+        recorder.record(run_time(), dispatcher.name(), &scenario, None, None);
+        // The recording is complete before anyone is told the run finished
+        let _ = tokio::task::spawn_blocking(move || recorder.finish()).await;
+    }
+
+    session.publish(Event::Finished {
+        kpis: session.kpis(),
+    });
+    Ok(())
+}
+
+/// Streams a recorded run with its original pacing, without contacting the runner.
+/// Clients can pause it, change the playback speed and ask for snapshots.
+pub(crate) async fn replay_recording(
+    mut replay: Replay,
+    session: Arc<Session>,
+    mut commands: Receiver<SessionCommand>,
+) -> Result<(), Box<dyn Error>> {
+    let mut speed = 1.0;
+    let mut paused = false;
+
+    while let Some(tick) = replay.next_tick() {
+        let (mut remaining, tick) = tick?;
+
+        // Wait until the tick is due, `remaining` is measured in recorded time
+        loop {
+            let waiting_since = Instant::now();
+            tokio::select! {
+                _ = sleep(remaining.div_f64(speed)), if !paused => break,
+                command = commands.recv() => {
+                    let Some(command) = command else {
+                        return Err("Session closed".into());
+                    };
+                    if !paused {
+                        let waited = waiting_since.elapsed().mul_f64(speed);
+                        remaining = remaining.saturating_sub(waited);
+                    }
+
+                    let message = &command.message;
+                    let reply = match message.command {
+                        Command::Pause => {
+                            paused = true;
+                            Event::ack(message)
+                        }
+                        Command::Resume => {
+                            paused = false;
+                            Event::ack(message)
+                        }
+                        Command::SetSpeed { speed: new_speed }
+                            if new_speed.is_finite() && new_speed > 0.0 =>
+                        {
+                            speed = new_speed;
+                            Event::ack(message)
+                        }
+                        Command::SetSpeed { .. } => {
+                            Event::error(message.id.clone(), "Speed must be a positive number")
+                        }
+                        Command::Resync => Event::ack(message),
+                        Command::Snapshot => Event::Snapshot {
+                            id: message.id.clone(),
+                            scenario: (*session.latest()).clone(),
+                        },
+                        Command::SetDispatcher { .. } | Command::Assign { .. } => Event::error(
+                            message.id.clone(),
+                            "Recorded runs cannot be changed",
+                        ),
+                    };
+                    command.reply(reply);
+                }
+            }
+        }
+
+        session.publish_tick(
+            tick.scenario.clone(),
+            tick.run_time(),
+            tick.dispatcher.as_deref(),
+        );
+        if let (Some(update), Some(response)) = (&tick.update, &tick.response) {
+            publish_assignments(&session, update, response, &[]);
+        }
+    }
+
+    session.publish(Event::Finished {
+        kpis: session.kpis(),
+    });
+    Ok(())
+}